@@ -0,0 +1,2 @@
+-- SHA-256 of the deposited blob, used to recognise retried deposits.
+ALTER TABLE messages ADD COLUMN body_hash BLOB;
@@ -0,0 +1,4 @@
+-- Deposit token (by hash) that wrote each message. NULL for rows predating this column.
+ALTER TABLE messages ADD COLUMN dep_hash BLOB;
+
+CREATE INDEX IF NOT EXISTS idx_messages_mailbox_dep_hash ON messages(mailbox_id, dep_hash);
@@ -0,0 +1,2 @@
+-- Opaque, client-encrypted label the owner attaches to a deposit token.
+ALTER TABLE deposit_tokens ADD COLUMN label BLOB;
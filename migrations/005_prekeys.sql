@@ -0,0 +1,22 @@
+-- X3DH prekeys hosted for the mailbox owner.
+
+CREATE TABLE IF NOT EXISTS signed_prekeys (
+  mailbox_id  TEXT PRIMARY KEY,
+  key_id      INTEGER NOT NULL,
+  public_key  BLOB NOT NULL,
+  signature   BLOB NOT NULL,
+  uploaded_at INTEGER NOT NULL,
+  FOREIGN KEY (mailbox_id) REFERENCES mailboxes(mailbox_id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS one_time_prekeys (
+  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+  mailbox_id  TEXT NOT NULL,
+  key_id      INTEGER NOT NULL,
+  public_key  BLOB NOT NULL,
+  created_at  INTEGER NOT NULL,
+  FOREIGN KEY (mailbox_id) REFERENCES mailboxes(mailbox_id) ON DELETE CASCADE,
+  UNIQUE (mailbox_id, key_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_one_time_prekeys_mailbox_id_id ON one_time_prekeys(mailbox_id, id);
@@ -0,0 +1,15 @@
+-- Small immutable public blobs (e.g. signed ContactCards), addressed by SHA-256.
+-- The same content may be held by several mailboxes, each with its own expiry.
+
+CREATE TABLE IF NOT EXISTS blobs (
+  mailbox_id  TEXT NOT NULL,
+  hash        BLOB NOT NULL,
+  content     BLOB NOT NULL,
+  created_at  INTEGER NOT NULL,
+  expires_at  INTEGER NOT NULL,
+  PRIMARY KEY (mailbox_id, hash),
+  FOREIGN KEY (mailbox_id) REFERENCES mailboxes(mailbox_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_blobs_hash ON blobs(hash);
+CREATE INDEX IF NOT EXISTS idx_blobs_expires ON blobs(expires_at);
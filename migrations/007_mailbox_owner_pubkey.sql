@@ -0,0 +1,2 @@
+-- Optional Ed25519 public key allowed to sign owner requests.
+ALTER TABLE mailboxes ADD COLUMN owner_pubkey BLOB;
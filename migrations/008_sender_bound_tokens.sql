@@ -0,0 +1,4 @@
+-- Optional Ed25519 key of the sender a deposit token is bound to; deposits
+-- with such a token must carry the sender's signature, kept for attribution.
+ALTER TABLE deposit_tokens ADD COLUMN sender_pubkey BLOB;
+ALTER TABLE messages ADD COLUMN sender_sig BLOB;
@@ -0,0 +1,4 @@
+-- Per-mailbox nonce from which the macaroon root key is derived
+-- (HMAC(server_secret, "macaroon" || mailbox_id || nonce)). Replacing it
+-- invalidates every macaroon minted for the mailbox.
+ALTER TABLE mailboxes ADD COLUMN macaroon_nonce BLOB;
@@ -0,0 +1,12 @@
+-- Additional owner credentials limited to a scope set (comma-separated:
+-- poll, ack, manage). Stored hashed like poll tokens.
+
+CREATE TABLE IF NOT EXISTS owner_credentials (
+  mailbox_id  TEXT NOT NULL,
+  cred_hash   BLOB NOT NULL,
+  scopes      TEXT NOT NULL,
+  revoked     INTEGER NOT NULL DEFAULT 0,
+  created_at  INTEGER NOT NULL,
+  PRIMARY KEY (mailbox_id, cred_hash),
+  FOREIGN KEY (mailbox_id) REFERENCES mailboxes(mailbox_id) ON DELETE CASCADE
+);
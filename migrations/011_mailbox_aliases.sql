@@ -0,0 +1,21 @@
+-- Revocable alias ids routing deposits to an underlying mailbox. An alias
+-- with rows in alias_deposit_tokens only accepts those deposit tokens.
+
+CREATE TABLE IF NOT EXISTS mailbox_aliases (
+  alias_id    TEXT PRIMARY KEY,
+  mailbox_id  TEXT NOT NULL,
+  revoked     INTEGER NOT NULL DEFAULT 0,
+  created_at  INTEGER NOT NULL,
+  FOREIGN KEY (mailbox_id) REFERENCES mailboxes(mailbox_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_mailbox_aliases_mailbox_id ON mailbox_aliases(mailbox_id);
+
+CREATE TABLE IF NOT EXISTS alias_deposit_tokens (
+  alias_id    TEXT NOT NULL,
+  dep_hash    BLOB NOT NULL,
+  PRIMARY KEY (alias_id, dep_hash),
+  FOREIGN KEY (alias_id) REFERENCES mailbox_aliases(alias_id) ON DELETE CASCADE
+);
+
+ALTER TABLE messages ADD COLUMN alias_id TEXT;
@@ -0,0 +1,17 @@
+-- Optional epoch-rotating addressing. Contacts derive the address for epoch
+-- e as HMAC(rotation_secret, e); the server keeps the addresses of adjacent
+-- epochs materialised so deposits can be resolved by lookup.
+ALTER TABLE mailboxes ADD COLUMN rotation_secret BLOB;
+ALTER TABLE mailboxes ADD COLUMN rotation_epoch_secs INTEGER;
+
+CREATE TABLE IF NOT EXISTS epoch_addresses (
+  address     TEXT PRIMARY KEY,
+  mailbox_id  TEXT NOT NULL,
+  epoch       INTEGER NOT NULL,
+  not_before  INTEGER NOT NULL,
+  not_after   INTEGER NOT NULL,
+  FOREIGN KEY (mailbox_id) REFERENCES mailboxes(mailbox_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_epoch_addresses_mailbox_id ON epoch_addresses(mailbox_id);
+CREATE INDEX IF NOT EXISTS idx_epoch_addresses_not_after ON epoch_addresses(not_after);
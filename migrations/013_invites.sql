@@ -0,0 +1,11 @@
+-- Operator-issued invite codes gating mailbox creation. Only the HMAC of
+-- the code is stored; `tier` is copied onto the mailbox it creates.
+CREATE TABLE IF NOT EXISTS invites (
+  code_hash   BLOB PRIMARY KEY,
+  uses_left   INTEGER NOT NULL,
+  tier        TEXT,
+  expires_at  INTEGER,
+  created_at  INTEGER NOT NULL
+);
+
+ALTER TABLE mailboxes ADD COLUMN tier TEXT;
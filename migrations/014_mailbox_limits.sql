@@ -0,0 +1,11 @@
+-- Per-mailbox limits. The first four are the allowance assigned from the
+-- mailbox's tier at creation (NULL = server defaults); the owner_* columns
+-- are lower values chosen by the owner. Effective limit = the smaller one.
+ALTER TABLE mailboxes ADD COLUMN max_msg_bytes INTEGER;
+ALTER TABLE mailboxes ADD COLUMN max_queue_bytes INTEGER;
+ALTER TABLE mailboxes ADD COLUMN ttl_days INTEGER;
+ALTER TABLE mailboxes ADD COLUMN max_ttl_days INTEGER;
+ALTER TABLE mailboxes ADD COLUMN owner_max_msg_bytes INTEGER;
+ALTER TABLE mailboxes ADD COLUMN owner_max_queue_bytes INTEGER;
+ALTER TABLE mailboxes ADD COLUMN owner_ttl_days INTEGER;
+ALTER TABLE mailboxes ADD COLUMN owner_max_ttl_days INTEGER;
@@ -0,0 +1,4 @@
+-- Per-mailbox cap on the number of queued messages (allowance from the
+-- tier, optionally lowered by the owner; NULL = server default).
+ALTER TABLE mailboxes ADD COLUMN max_queue_messages INTEGER;
+ALTER TABLE mailboxes ADD COLUMN owner_max_queue_messages INTEGER;
@@ -0,0 +1,8 @@
+-- Maintained per-mailbox queue usage, updated in the same transaction as
+-- every message insert and delete (and periodically recomputed).
+ALTER TABLE mailboxes ADD COLUMN queued_bytes INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE mailboxes ADD COLUMN queued_count INTEGER NOT NULL DEFAULT 0;
+
+UPDATE mailboxes SET
+  queued_bytes = (SELECT COALESCE(SUM(LENGTH(blob)), 0) FROM messages m WHERE m.mailbox_id = mailboxes.mailbox_id),
+  queued_count = (SELECT COUNT(*) FROM messages m WHERE m.mailbox_id = mailboxes.mailbox_id);
@@ -0,0 +1,9 @@
+-- Optional sender-supplied envelope hint, opaque to the server. Kept out of
+-- `messages` so headers-only polls don't read past the blob to reach it.
+CREATE TABLE IF NOT EXISTS message_hints (
+  mailbox_id TEXT NOT NULL,
+  msg_id     BLOB NOT NULL,
+  hint       BLOB NOT NULL,
+  PRIMARY KEY (mailbox_id, msg_id),
+  FOREIGN KEY (mailbox_id, msg_id) REFERENCES messages(mailbox_id, msg_id) ON DELETE CASCADE
+);
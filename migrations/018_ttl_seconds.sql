@@ -0,0 +1,11 @@
+-- Per-mailbox TTL limits move from whole days to seconds.
+ALTER TABLE mailboxes RENAME COLUMN ttl_days TO ttl_secs;
+ALTER TABLE mailboxes RENAME COLUMN max_ttl_days TO max_ttl_secs;
+ALTER TABLE mailboxes RENAME COLUMN owner_ttl_days TO owner_ttl_secs;
+ALTER TABLE mailboxes RENAME COLUMN owner_max_ttl_days TO owner_max_ttl_secs;
+
+UPDATE mailboxes SET
+  ttl_secs = ttl_secs * 86400,
+  max_ttl_secs = max_ttl_secs * 86400,
+  owner_ttl_secs = owner_ttl_secs * 86400,
+  owner_max_ttl_secs = owner_max_ttl_secs * 86400;
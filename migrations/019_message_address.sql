@@ -0,0 +1,3 @@
+-- Address a message was deposited to (alias or epoch address), as covered by
+-- its sender signature. NULL when deposited to the mailbox id itself.
+ALTER TABLE messages ADD COLUMN address TEXT;
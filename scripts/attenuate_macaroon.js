@@ -0,0 +1,38 @@
+#!/usr/bin/env node
+
+/**
+ * Attenuate a mailbox macaroon offline by appending caveats.
+ * Usage: attenuate_macaroon.js <macaroon> <caveat> [caveat...]
+ * Caveats: ops=poll,ack,manage,deposit | expires=<unix ts> | max_msg_bytes=<n> | deposit_until=<unix ts>
+ */
+
+import { createHmac } from "crypto";
+
+function base64urlDecode(str) {
+  const base64 = str.replace(/-/g, '+').replace(/_/g, '/');
+  const padding = '='.repeat((4 - base64.length % 4) % 4);
+  return Buffer.from(base64 + padding, 'base64');
+}
+
+function base64urlEncode(buf) {
+  return Buffer.from(buf).toString('base64')
+    .replace(/\+/g, '-')
+    .replace(/\//g, '_')
+    .replace(/=/g, '');
+}
+
+const [macaroon, ...caveats] = process.argv.slice(2);
+if (!macaroon || caveats.length === 0) {
+  console.error("Usage: attenuate_macaroon.js <macaroon> <caveat> [caveat...]");
+  process.exit(1);
+}
+
+const m = JSON.parse(base64urlDecode(macaroon).toString("utf8"));
+let sig = base64urlDecode(m.sig);
+for (const c of caveats) {
+  sig = createHmac("sha256", sig).update(c, "utf8").digest();
+  m.caveats.push(c);
+}
+m.sig = base64urlEncode(sig);
+
+console.log(base64urlEncode(Buffer.from(JSON.stringify(m), "utf8")));
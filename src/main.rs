@@ -1,20 +1,40 @@
 use axum::{
-    body::Bytes,
-    extract::{Path, Query, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
+    body::{Body, Bytes},
+    extract::{
+        FromRequest, FromRequestParts, Multipart, Path, Query, RawPathParams, Request, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
 };
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ed25519_dalek::{Signature, VerifyingKey};
 use hmac::{Hmac, Mac};
+use lru::LruCache;
 use rand::RngCore;
-use serde::{Deserialize, Serialize};
-use sha2::Sha256;
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Row, Sqlite};
-use std::{env, sync::Arc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    Connection, Pool, QueryBuilder, Row, Sqlite, SqliteConnection, Transaction,
+};
+use std::{
+    collections::HashMap,
+    env,
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
 use thiserror::Error;
-use time::OffsetDateTime;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::{mpsc, oneshot};
 use tower_http::trace::TraceLayer;
 use tracing::info;
 
@@ -22,14 +42,32 @@ type HmacSha256 = Hmac<Sha256>;
 
 #[derive(Clone)]
 struct AppState {
-    db: Pool<Sqlite>,
+    shards: Vec<Shard>, // mailboxes are placed by `shard_index`; invites live on shard 0
     server_secret: Vec<u8>,
-    default_ttl_days: i64,
-    max_ttl_days: i64,
+    default_ttl_secs: i64,
+    max_ttl_secs: i64,
     max_msg_bytes: usize,
     max_queue_bytes: i64,
+    max_queue_messages: i64,
     poll_limit_default: i64,
     poll_limit_max: i64,
+    prekey_low_watermark: i64,
+    max_one_time_prekeys: i64,
+    max_blob_bytes: usize,
+    max_blobs_per_mailbox: i64,
+    default_blob_ttl_days: i64,
+    max_blob_ttl_days: i64,
+    privacy_mode: bool,
+    auth_failure_floor: Duration,
+    owner_sig_max_skew_secs: i64,
+    owner_nonces: Arc<Mutex<HashMap<String, i64>>>, // "mailbox_id[:sender]:nonce" -> expiry
+    admin_token: Option<String>,
+    require_invite: bool,
+    pow_difficulty_bits: u32, // 0 = no proof-of-work
+    pow_challenge_ttl_secs: i64,
+    pow_spent: Arc<Mutex<HashMap<Vec<u8>, i64>>>, // challenge -> expiry
+    tiers: HashMap<String, TierLimits>,
+    auth_cache: Arc<AuthCache>,
 }
 
 #[tokio::main]
@@ -48,61 +86,194 @@ async fn main() -> anyhow::Result<()> {
         .expect("SERVER_SECRET is required (recommend 32+ bytes)")
         .into_bytes();
 
-    let default_ttl_days = env_i64("DEFAULT_TTL_DAYS", 7);
-    let max_ttl_days = env_i64("MAX_TTL_DAYS", 14);
+    // Message TTLs are in seconds; the older whole-day variables still apply
+    // when the *_SECS ones are unset.
+    let default_ttl_secs = env_i64("DEFAULT_TTL_SECS", env_i64("DEFAULT_TTL_DAYS", 7) * 86_400);
+    let max_ttl_secs = env_i64("MAX_TTL_SECS", env_i64("MAX_TTL_DAYS", 14) * 86_400);
     let max_msg_bytes = env_usize("MAX_MSG_BYTES", 16_384);
     let max_queue_bytes = env_i64("MAX_QUEUE_BYTES", 10_485_760);
+    let max_queue_messages = env_i64("MAX_QUEUE_MESSAGES", 10_000);
     let poll_limit_default = env_i64("POLL_LIMIT_DEFAULT", 20);
     let poll_limit_max = env_i64("POLL_LIMIT_MAX", 50);
+    let prekey_low_watermark = env_i64("PREKEY_LOW_WATERMARK", 10);
+    let max_one_time_prekeys = env_i64("MAX_ONE_TIME_PREKEYS", 500);
+    let max_blob_bytes = env_usize("MAX_BLOB_BYTES", 65_536);
+    let max_blobs_per_mailbox = env_i64("MAX_BLOBS_PER_MAILBOX", 16);
+    let default_blob_ttl_days = env_i64("DEFAULT_BLOB_TTL_DAYS", 30);
+    let max_blob_ttl_days = env_i64("MAX_BLOB_TTL_DAYS", 365);
+    let owner_sig_max_skew_secs = env_i64("OWNER_SIG_MAX_SKEW_SECS", 300);
+    let privacy_mode = env_i64("PRIVACY_MODE", 0) != 0;
+    let auth_failure_floor = Duration::from_millis(env_i64("AUTH_FAILURE_FLOOR_MS", 100) as u64);
+    let admin_token = env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+    let require_invite = env_i64("REQUIRE_INVITE", 0) != 0;
+    let pow_difficulty_bits = env_i64("POW_DIFFICULTY_BITS", 0).clamp(0, 64) as u32;
+    let pow_challenge_ttl_secs = env_i64("POW_CHALLENGE_TTL_SECS", 300);
+    // e.g. {"pro":{"max_msg_bytes":65536,"max_ttl_secs":2592000}}; unset fields use the defaults
+    let tiers: HashMap<String, TierLimits> = match env::var("QUOTA_TIERS") {
+        Ok(v) if !v.is_empty() => {
+            serde_json::from_str(&v).expect("QUOTA_TIERS must be a JSON object of tiers")
+        }
+        _ => HashMap::new(),
+    };
+    if tiers
+        .values()
+        .any(|t| t.ttl_days.is_some() || t.max_ttl_days.is_some())
+    {
+        tracing::warn!(
+            "QUOTA_TIERS ttl_days/max_ttl_days are deprecated, use ttl_secs/max_ttl_secs"
+        );
+    }
 
-    let db = SqlitePoolOptions::new()
-        .max_connections(10)
-        .connect(&database_url)
-        .await?;
-    sqlx::query("PRAGMA journal_mode = WAL;")
-        .execute(&db)
-        .await?;
+    let busy_timeout = Duration::from_millis(env_i64("SQLITE_BUSY_TIMEOUT_MS", 5_000) as u64);
+    let write_batch_max = env_usize("WRITE_BATCH_MAX", 256).max(1);
 
-    sqlx::migrate!("./migrations").run(&db).await?;
+    let shard_count = env_usize("SHARD_COUNT", 1).max(1);
+    let auth_cache = AuthCache::new(
+        env_usize("AUTH_CACHE_CAPACITY", 100_000),
+        env_i64("AUTH_CACHE_TTL_SECS", 60),
+    );
 
-    // background TTL purge (best-effort)
-    {
-        let db_clone = db.clone();
+    let connect_opts = SqliteConnectOptions::from_str(&database_url)?
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(busy_timeout);
+
+    // Offline re-shard: `whisper-mailbox reshard <new_shard_count>`
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("reshard") {
+        let to: usize = args
+            .get(2)
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n >= 1)
+            .ok_or_else(|| anyhow::anyhow!("usage: whisper-mailbox reshard <new_shard_count>"))?;
+        return reshard(&connect_opts, shard_count, to).await;
+    }
+
+    let mut shards = Vec::with_capacity(shard_count);
+    for index in 0..shard_count {
+        let opts = shard_options(&connect_opts, index, shard_count);
+        shards.push(Shard::open(opts, write_batch_max).await?);
+    }
+
+    // background TTL purge (best-effort), per shard
+    for shard in &shards {
+        let db_clone = shard.write_db.clone();
         tokio::spawn(async move {
-            loop {
+            for tick in 0u64.. {
                 let now = unix_ts();
-                let _ = sqlx::query("DELETE FROM messages WHERE expires_at <= ?")
+                if let Err(e) = purge_expired_messages(&db_clone, now).await {
+                    tracing::warn!(error = %e, "message purge failed");
+                }
+                let _ = sqlx::query("DELETE FROM blobs WHERE expires_at <= ?")
                     .bind(now)
                     .execute(&db_clone)
                     .await;
+                if let Err(e) = refresh_epoch_addresses(&db_clone, None, now).await {
+                    tracing::warn!(error = %e, "epoch address refresh failed");
+                }
+                // Hourly (and at startup): repair the queue counters
+                if tick % 60 == 0 {
+                    match recount_queues(&db_clone).await {
+                        Ok(0) => {}
+                        Ok(n) => tracing::warn!(mailboxes = n, "repaired drifted queue counters"),
+                        Err(e) => tracing::warn!(error = %e, "queue recount failed"),
+                    }
+                }
                 tokio::time::sleep(std::time::Duration::from_secs(60)).await;
             }
         });
     }
 
     let state = AppState {
-        db,
+        shards,
         server_secret,
-        default_ttl_days,
-        max_ttl_days,
+        default_ttl_secs,
+        max_ttl_secs,
         max_msg_bytes,
         max_queue_bytes,
+        max_queue_messages,
         poll_limit_default,
         poll_limit_max,
+        prekey_low_watermark,
+        max_one_time_prekeys,
+        max_blob_bytes,
+        max_blobs_per_mailbox,
+        default_blob_ttl_days,
+        max_blob_ttl_days,
+        privacy_mode,
+        auth_failure_floor,
+        owner_sig_max_skew_secs,
+        owner_nonces: Arc::new(Mutex::new(HashMap::new())),
+        admin_token,
+        require_invite,
+        pow_difficulty_bits,
+        pow_challenge_ttl_secs,
+        pow_spent: Arc::new(Mutex::new(HashMap::new())),
+        tiers,
+        auth_cache: Arc::new(auth_cache),
     };
+    let state = Arc::new(state);
 
     let app = Router::new()
         .route("/v1/mailboxes", post(create_mailbox))
+        .route("/v1/pow-challenge", get(pow_challenge))
+        .route("/v1/admin/invites", get(list_invites).post(create_invites))
+        .route("/v1/admin/invites/:invite_hash", delete(revoke_invite))
         .route(
             "/v1/mailboxes/:mailbox_id/deposit-tokens",
-            post(register_deposit_tokens),
+            get(list_deposit_tokens).post(register_deposit_tokens),
         )
+        .route("/v1/mailboxes/:mailbox_id/status", get(mailbox_status))
+        .route("/v1/mailboxes/:mailbox_id/limits", post(set_limits))
         .route("/v1/mailboxes/:mailbox_id/deposit", post(deposit))
         .route("/v1/mailboxes/:mailbox_id/poll", get(poll))
+        .route(
+            "/v1/mailboxes/:mailbox_id/messages/:msg_id",
+            get(fetch_message),
+        )
         .route("/v1/mailboxes/:mailbox_id/ack", post(ack))
         .route("/v1/mailboxes/:mailbox_id/revoke", post(revoke))
+        .route("/v1/mailboxes/:mailbox_id/revoke-purge", post(revoke_purge))
+        .route(
+            "/v1/mailboxes/:mailbox_id/prekeys",
+            get(prekey_status).post(upload_prekeys),
+        )
+        .route(
+            "/v1/mailboxes/:mailbox_id/prekey-bundle",
+            post(claim_prekey_bundle),
+        )
+        .route("/v1/mailboxes/:mailbox_id/blobs", post(upload_blob))
+        .route("/v1/mailboxes/:mailbox_id/blobs/:hash", delete(delete_blob))
+        .route("/v1/blobs/:hash", get(get_blob))
+        .route(
+            "/v1/mailboxes/:mailbox_id/macaroons",
+            post(mint_macaroon).delete(revoke_macaroons),
+        )
+        .route(
+            "/v1/mailboxes/:mailbox_id/credentials",
+            get(list_credentials).post(create_credential),
+        )
+        .route(
+            "/v1/mailboxes/:mailbox_id/credentials/:cred_hash",
+            delete(revoke_credential),
+        )
+        .route(
+            "/v1/mailboxes/:mailbox_id/aliases",
+            get(list_aliases).post(create_alias),
+        )
+        .route(
+            "/v1/mailboxes/:mailbox_id/aliases/:alias_id",
+            delete(revoke_alias),
+        )
+        .route(
+            "/v1/mailboxes/:mailbox_id/rotation",
+            post(enable_rotation).delete(disable_rotation),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            verify_owner_signature,
+        ))
         .layer(TraceLayer::new_for_http())
-        .with_state(Arc::new(state));
+        .with_state(state);
 
     info!("listening on {}", bind_addr);
     let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
@@ -142,6 +313,11 @@ enum ApiError {
     RateLimited,
     #[error("conflict")]
     Conflict,
+    // The mailbox is full: retrying won't help until the owner drains it.
+    // The reason ("queue_bytes", "queue_messages", "blobs", "one_time_prekeys")
+    // is returned too.
+    #[error("quota exceeded")]
+    QuotaExceeded(&'static str),
     #[error("server error")]
     ServerError,
 }
@@ -156,12 +332,87 @@ impl IntoResponse for ApiError {
             ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
             ApiError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
             ApiError::Conflict => StatusCode::CONFLICT,
+            ApiError::QuotaExceeded(reason) => {
+                let body = serde_json::json!({ "error": self.to_string(), "reason": reason });
+                return (StatusCode::INSUFFICIENT_STORAGE, Json(body)).into_response();
+            }
             ApiError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
         };
         (status, Json(serde_json::json!({ "error": self.to_string() }))).into_response()
     }
 }
 
+fn has_content_type(headers: &HeaderMap, mime: &str) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|t| t.trim().eq_ignore_ascii_case(mime))
+}
+
+// Request body in JSON (default) or, with `Content-Type: application/cbor`,
+// CBOR with the same field names and value encodings.
+struct Payload<T>(T);
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S> for Payload<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_content_type(req.headers(), CBOR) {
+            let Json(value) = Json::<T>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Payload(value));
+        }
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        ciborium::from_reader(body.as_ref())
+            .map(Payload)
+            .map_err(|_| ApiError::InvalidInput.into_response())
+    }
+}
+
+// Like Payload, but an empty body means `T::default()`. Any other body must
+// parse, or the request fails with 400: a mistyped body must not be taken for
+// "no options" when the options restrict what gets created.
+struct OptionalPayload<T>(T);
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S> for OptionalPayload<T>
+where
+    T: DeserializeOwned + Default,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let cbor = has_content_type(req.headers(), CBOR);
+        let json = has_content_type(req.headers(), "application/json");
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if body.is_empty() {
+            return Ok(OptionalPayload(T::default()));
+        }
+        let value = if cbor {
+            ciborium::from_reader(body.as_ref()).ok()
+        } else if json {
+            serde_json::from_slice(&body).ok()
+        } else {
+            None
+        };
+        value
+            .map(OptionalPayload)
+            .ok_or_else(|| ApiError::InvalidInput.into_response())
+    }
+}
+
 fn bearer_token(headers: &HeaderMap) -> Result<String, ApiError> {
     let auth = headers.get("authorization").ok_or(ApiError::Unauthorized)?;
     let auth = auth.to_str().map_err(|_| ApiError::Unauthorized)?;
@@ -172,539 +423,4684 @@ fn bearer_token(headers: &HeaderMap) -> Result<String, ApiError> {
     Ok(parts[1].to_string())
 }
 
-fn hmac_hash(server_secret: &[u8], token: &[u8]) -> Vec<u8> {
-    let mut mac = HmacSha256::new_from_slice(server_secret).expect("HMAC key");
-    mac.update(token);
-    mac.finalize().into_bytes().to_vec()
+// Operation a request performs, checked against macaroon caveats and
+// scoped credentials. `Admin` (credential and macaroon management) is never
+// grantable to a scoped credential.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Poll,
+    Ack,
+    Manage,
+    Deposit,
+    Admin,
 }
 
-fn b64url_decode(s: &str) -> Result<Vec<u8>, ApiError> {
-    URL_SAFE_NO_PAD
-        .decode(s.as_bytes())
-        .map_err(|_| ApiError::InvalidInput)
-}
-fn b64url_encode(bytes: &[u8]) -> String {
-    URL_SAFE_NO_PAD.encode(bytes)
+impl Op {
+    fn parse(s: &str) -> Option<Op> {
+        match s {
+            "poll" => Some(Op::Poll),
+            "ack" => Some(Op::Ack),
+            "manage" => Some(Op::Manage),
+            "deposit" => Some(Op::Deposit),
+            _ => None,
+        }
+    }
 }
 
-fn random_b64url(nbytes: usize) -> String {
-    let mut buf = vec![0u8; nbytes];
-    rand::thread_rng().fill_bytes(&mut buf);
-    b64url_encode(&buf)
+// Bounded LRU of successful auth lookups so hot mailboxes skip the database.
+// Token keys are the HMAC hashes already stored in the database. Handlers that
+// revoke or change what a key grants invalidate it; the TTL bounds staleness
+// when several processes share the database. A lookup takes the cache's
+// generation before reading the database and only caches its result if no
+// invalidation happened since, so it can't re-insert a row read just before
+// a revocation committed.
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum AuthKey {
+    Owner(String, Vec<u8>),   // mailbox_id, HMAC(poll token or credential)
+    Deposit(String, Vec<u8>), // mailbox_id, HMAC(deposit token)
+    Address(String),          // mailbox id or alias id deposited to
+    AliasTokens(String),      // alias_id
+    Limits(String),           // mailbox_id
 }
 
-// Cursor = base64url( last_id (8 bytes LE) || mac(32 bytes) )
-// mac = HMAC(server_secret, "cursor" || mailbox_id || last_id_bytes)
-fn cursor_encode(server_secret: &[u8], mailbox_id: &str, last_id: i64) -> String {
-    let last = (last_id as u64).to_le_bytes();
-    let mut mac = HmacSha256::new_from_slice(server_secret).unwrap();
-    mac.update(b"cursor");
-    mac.update(mailbox_id.as_bytes());
-    mac.update(&last);
-    let tag = mac.finalize().into_bytes();
-
-    let mut out = Vec::with_capacity(8 + 32);
-    out.extend_from_slice(&last);
-    out.extend_from_slice(&tag);
-    b64url_encode(&out)
+#[derive(Clone)]
+enum AuthEntry {
+    Owner(Option<String>),           // None: poll token; Some: credential scopes
+    Deposit(Option<Vec<u8>>),        // sender_pubkey
+    Address(String, Option<String>), // mailbox_id, alias_id
+    AliasTokens(Vec<Vec<u8>>),
+    Limits(Limits, Limits), // allowance, effective
 }
 
-fn cursor_decode(server_secret: &[u8], mailbox_id: &str, cursor: &str) -> Result<i64, ApiError> {
-    let raw = b64url_decode(cursor)?;
-    if raw.len() != 40 {
-        return Err(ApiError::InvalidInput);
+impl AuthEntry {
+    fn mailbox_id(&self) -> Option<&str> {
+        match self {
+            AuthEntry::Address(mailbox_id, _) => Some(mailbox_id),
+            _ => None,
+        }
     }
-    let (last_bytes, tag) = raw.split_at(8);
-
-    let mut mac = HmacSha256::new_from_slice(server_secret).unwrap();
-    mac.update(b"cursor");
-    mac.update(mailbox_id.as_bytes());
-    mac.update(last_bytes);
-    mac.verify_slice(tag).map_err(|_| ApiError::InvalidInput)?;
-
-    let mut arr = [0u8; 8];
-    arr.copy_from_slice(last_bytes);
-    let last_id = u64::from_le_bytes(arr) as i64;
-    Ok(last_id)
 }
 
-#[derive(Deserialize)]
-struct CreateMailboxReq {
-    poll_token: Option<String>,
+struct AuthCache {
+    entries: Option<Mutex<LruCache<AuthKey, (AuthEntry, i64)>>>, // None = disabled
+    ttl_secs: i64,
+    generation: AtomicU64, // bumped by every invalidation
 }
 
-#[derive(Serialize)]
-struct Limits {
-    max_msg_bytes: usize,
-    max_queue_bytes: i64,
-    ttl_days: i64,
-}
+impl AuthCache {
+    fn new(capacity: usize, ttl_secs: i64) -> AuthCache {
+        AuthCache {
+            entries: NonZeroUsize::new(capacity).map(|c| Mutex::new(LruCache::new(c))),
+            ttl_secs,
+            generation: AtomicU64::new(0),
+        }
+    }
 
-#[derive(Serialize)]
-struct CreateMailboxResp {
-    mailbox_id: String,
-    poll_token: Option<String>,
-    limits: Limits,
-}
+    // Taken before the database lookup whose result is passed to `put`
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
 
-async fn create_mailbox(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<CreateMailboxReq>,
-) -> Result<Json<CreateMailboxResp>, ApiError> {
-    let now = unix_ts();
-    let mailbox_id = random_b64url(24);
+    fn get(&self, key: &AuthKey) -> Option<AuthEntry> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        match entries.get(key) {
+            Some((entry, expires)) if *expires > unix_ts() => Some(entry.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
 
-    let (poll_token, poll_hash) = match req.poll_token {
-        Some(t) => {
-            let raw = b64url_decode(&t)?;
-            if raw.len() != 32 {
-                return Err(ApiError::InvalidInput);
+    fn put(&self, key: AuthKey, entry: AuthEntry, generation: u64) {
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            if self.generation() != generation {
+                return;
             }
-            (None, hmac_hash(&state.server_secret, &raw))
+            entries.put(key, (entry, unix_ts() + self.ttl_secs));
         }
-        None => {
-            let mut raw = [0u8; 32];
-            rand::thread_rng().fill_bytes(&mut raw);
-            let token_b64 = b64url_encode(&raw);
-            (Some(token_b64), hmac_hash(&state.server_secret, &raw))
+    }
+
+    fn invalidate(&self, key: &AuthKey) {
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            entries.pop(key);
         }
-    };
+    }
 
-    sqlx::query("INSERT INTO mailboxes (mailbox_id, poll_hash, created_at) VALUES (?, ?, ?)")
-        .bind(&mailbox_id)
-        .bind(poll_hash)
-        .bind(now)
-        .execute(&state.db)
-        .await
-        .map_err(|_| ApiError::ServerError)?;
+    // Drops every entry for a mailbox, including addresses that resolve to it
+    fn invalidate_mailbox(&self, mailbox_id: &str) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let mut entries = entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let stale: Vec<AuthKey> = entries
+            .iter()
+            .filter(|(key, (entry, _))| match key {
+                AuthKey::Owner(m, _) | AuthKey::Deposit(m, _) | AuthKey::Limits(m) => {
+                    m == mailbox_id
+                }
+                AuthKey::Address(a) => a == mailbox_id || entry.mailbox_id() == Some(mailbox_id),
+                AuthKey::AliasTokens(_) => false,
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            entries.pop(&key);
+        }
+    }
+}
 
-    Ok(Json(CreateMailboxResp {
-        mailbox_id,
-        poll_token,
-        limits: Limits {
-            max_msg_bytes: state.max_msg_bytes,
-            max_queue_bytes: state.max_queue_bytes,
-            ttl_days: state.default_ttl_days,
-        },
-    }))
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
 }
 
-#[derive(Deserialize)]
-struct RegisterDepositTokensReq {
-    deposit_tokens: Vec<String>, // base64url(32 bytes)
+impl AppState {
+    // In privacy mode every authorization failure looks the same (status,
+    // body and, via a latency floor, timing) so mailbox ids can't be probed.
+    async fn auth_failure(&self, e: ApiError, started: Instant) -> ApiError {
+        if !self.privacy_mode || matches!(e, ApiError::ServerError) {
+            return e;
+        }
+        tokio::time::sleep_until((started + self.auth_failure_floor).into()).await;
+        ApiError::Forbidden
+    }
 }
 
-#[derive(Serialize)]
-struct RegisterDepositTokensResp {
-    added: u64,
+// Owner auth: a request signed with the mailbox's owner key (already
+// verified by `verify_owner_signature`), a macaroon whose caveats allow `op`,
+// or the bearer poll token checked against the stored hash.
+async fn auth_owner(
+    state: &AppState,
+    mailbox_id: &str,
+    headers: &HeaderMap,
+    signed: Option<&SignedOwner>,
+    op: Op,
+) -> Result<(), ApiError> {
+    let started = Instant::now();
+    match check_owner(state, mailbox_id, headers, signed, op).await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(state.auth_failure(e, started).await),
+    }
 }
 
-async fn register_deposit_tokens(
-    State(state): State<Arc<AppState>>,
-    Path(mailbox_id): Path<String>,
-    headers: HeaderMap,
-    Json(req): Json<RegisterDepositTokensReq>,
-) -> Result<Json<RegisterDepositTokensResp>, ApiError> {
-    let token = bearer_token(&headers)?;
+async fn check_owner(
+    state: &AppState,
+    mailbox_id: &str,
+    headers: &HeaderMap,
+    signed: Option<&SignedOwner>,
+    op: Op,
+) -> Result<(), ApiError> {
+    if let Some(signed) = signed {
+        if signed.mailbox_id == mailbox_id {
+            return Ok(());
+        }
+        return Err(ApiError::Forbidden);
+    }
+    if let Some(m) = macaroon_token(headers) {
+        return verify_macaroon(state, mailbox_id, m, op, 0).await;
+    }
+
+    let token = bearer_token(headers)?;
     let token_raw = b64url_decode(&token)?;
     if token_raw.len() != 32 {
         return Err(ApiError::Unauthorized);
     }
     let poll_hash = hmac_hash(&state.server_secret, &token_raw);
 
+    let key = AuthKey::Owner(mailbox_id.to_string(), poll_hash.clone());
+    match state.auth_cache.get(&key) {
+        Some(AuthEntry::Owner(None)) => return Ok(()),
+        Some(AuthEntry::Owner(Some(scopes))) => {
+            if scopes.split(',').any(|sc| Op::parse(sc) == Some(op)) {
+                return Ok(());
+            }
+            return Err(ApiError::Forbidden);
+        }
+        _ => {}
+    }
+
+    let generation = state.auth_cache.generation();
     let mb: Option<(Vec<u8>,)> =
         sqlx::query_as("SELECT poll_hash FROM mailboxes WHERE mailbox_id = ?")
-            .bind(&mailbox_id)
-            .fetch_optional(&state.db)
+            .bind(mailbox_id)
+            .fetch_optional(&state.shard(mailbox_id).db)
             .await
             .map_err(|_| ApiError::ServerError)?;
-    let Some((stored_hash,)) = mb else {
-        return Err(ApiError::NotFound);
+
+    // Unknown mailboxes go through the same comparisons and queries
+    let (exists, stored_hash) = match mb {
+        Some((h,)) => (true, h),
+        None => (false, vec![0u8; 32]),
     };
+    if ct_eq(&stored_hash, &poll_hash) && exists {
+        state
+            .auth_cache
+            .put(key, AuthEntry::Owner(None), generation);
+        return Ok(());
+    }
 
-    if stored_hash != poll_hash {
+    // Scoped credential?
+    let cred: Option<(String,)> = sqlx::query_as(
+        "SELECT scopes FROM owner_credentials WHERE mailbox_id = ? AND cred_hash = ? AND revoked = 0",
+    )
+    .bind(mailbox_id)
+    .bind(&poll_hash)
+    .fetch_optional(&state.shard(mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    if !exists {
+        return Err(ApiError::NotFound);
+    }
+    let Some((scopes,)) = cred else {
+        return Err(ApiError::Forbidden);
+    };
+    let allowed = scopes.split(',').any(|sc| Op::parse(sc) == Some(op));
+    state
+        .auth_cache
+        .put(key, AuthEntry::Owner(Some(scopes)), generation);
+    if !allowed {
         return Err(ApiError::Forbidden);
     }
+    Ok(())
+}
 
-    if req.deposit_tokens.is_empty() || req.deposit_tokens.len() > 5000 {
-        return Err(ApiError::InvalidInput);
+// Registered, unrevoked deposit token (or deposit-capable macaroon)
+// presented on a request.
+struct Depositor {
+    mailbox_id: String,             // underlying mailbox, after alias resolution
+    alias_id: Option<String>,       // set when addressed through an alias
+    dep_hash: Option<Vec<u8>>,      // None for macaroons
+    sender_pubkey: Option<Vec<u8>>, // set for sender-bound tokens
+}
+
+// Resolves an address (mailbox id or live alias id) to its mailbox id and
+// alias, if any.
+async fn resolve_address(
+    state: &AppState,
+    address: &str,
+) -> Result<(String, Option<String>), ApiError> {
+    let key = AuthKey::Address(address.to_string());
+    if let Some(AuthEntry::Address(mailbox_id, alias_id)) = state.auth_cache.get(&key) {
+        return Ok((mailbox_id, alias_id));
     }
 
-    let now = unix_ts();
-    let mut added: u64 = 0;
+    let generation = state.auth_cache.generation();
+    let exists: Option<(String,)> =
+        sqlx::query_as("SELECT mailbox_id FROM mailboxes WHERE mailbox_id = ?")
+            .bind(address)
+            .fetch_optional(&state.shard(address).db)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+    if exists.is_some() {
+        state.auth_cache.put(
+            key,
+            AuthEntry::Address(address.to_string(), None),
+            generation,
+        );
+        return Ok((address.to_string(), None));
+    }
 
-    for t in req.deposit_tokens {
-        let raw = b64url_decode(&t)?;
-        if raw.len() != 32 {
-            return Err(ApiError::InvalidInput);
+    // Aliases and epoch addresses don't hash to their mailbox's shard.
+    // Epoch addresses aren't cached since they expire with their window.
+    let now = unix_ts();
+    for shard in &state.shards {
+        let alias: Option<(String,)> = sqlx::query_as(
+            "SELECT mailbox_id FROM mailbox_aliases WHERE alias_id = ? AND revoked = 0",
+        )
+        .bind(address)
+        .fetch_optional(&shard.db)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+        if let Some((mailbox_id,)) = alias {
+            let alias_id = Some(address.to_string());
+            let entry = AuthEntry::Address(mailbox_id.clone(), alias_id.clone());
+            state.auth_cache.put(key, entry, generation);
+            return Ok((mailbox_id, alias_id));
         }
-        let dep_hash = hmac_hash(&state.server_secret, &raw);
 
-        let res = sqlx::query(
-            "INSERT OR IGNORE INTO deposit_tokens (mailbox_id, dep_hash, revoked, created_at) VALUES (?, ?, 0, ?)",
+        let epoch: Option<(String,)> = sqlx::query_as(
+            "SELECT mailbox_id FROM epoch_addresses WHERE address = ? AND not_before <= ? AND not_after > ?",
         )
-        .bind(&mailbox_id)
-        .bind(dep_hash)
+        .bind(address)
+        .bind(now)
         .bind(now)
-        .execute(&state.db)
+        .fetch_optional(&shard.db)
         .await
         .map_err(|_| ApiError::ServerError)?;
-        added += res.rows_affected();
+        if let Some((mailbox_id,)) = epoch {
+            return Ok((mailbox_id, None));
+        }
     }
-
-    Ok(Json(RegisterDepositTokensResp { added }))
+    Err(ApiError::NotFound)
 }
 
-fn header_msg_id(headers: &HeaderMap) -> Result<Vec<u8>, ApiError> {
-    let v = headers
-        .get("x-whisper-msgid")
-        .ok_or(ApiError::InvalidInput)?
-        .to_str()
-        .map_err(|_| ApiError::InvalidInput)?;
-    let raw = b64url_decode(v)?;
-    if !(16..=32).contains(&raw.len()) {
-        return Err(ApiError::InvalidInput);
-    }
-    Ok(raw)
+// Address of a rotating mailbox for `epoch`:
+//   base64url(HMAC-SHA256(rotation_secret, "whisper-epoch-v1" || u64_be(epoch))[..24])
+fn epoch_address(rotation_secret: &[u8], epoch: i64) -> String {
+    let mut mac = HmacSha256::new_from_slice(rotation_secret).expect("HMAC key");
+    mac.update(b"whisper-epoch-v1");
+    mac.update(&(epoch as u64).to_be_bytes());
+    b64url_encode(&mac.finalize().into_bytes()[..24])
 }
 
-fn header_expires_at(headers: &HeaderMap) -> Option<i64> {
-    // Accept both spellings
-    let v = headers
-        .get("x-whisper-expiresat")
-        .or_else(|| headers.get("x-whisper-expires-at"))
-        .and_then(|h| h.to_str().ok())?;
+// One SQLite database. With SHARD_COUNT > 1 there are N files, each with its
+// own pools, writer, migrations and purge task, and a mailbox's rows all live
+// on shard `shard_index(mailbox_id, N)`.
+#[derive(Clone)]
+struct Shard {
+    db: Pool<Sqlite>,       // read-only
+    write_db: Pool<Sqlite>, // the single write connection
+    writer: Writer,         // group-committed deposits, acks and token registrations
+}
 
-    v.parse::<i64>().ok()
+impl Shard {
+    async fn open(opts: SqliteConnectOptions, write_batch_max: usize) -> anyhow::Result<Shard> {
+        // All writes share one connection; reads go to a read-only pool
+        let write_db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(opts.clone())
+            .await?;
+        sqlx::migrate!("./migrations").run(&write_db).await?;
+        let db = SqlitePoolOptions::new()
+            .max_connections(10)
+            .connect_with(opts.read_only(true))
+            .await?;
+        let writer = Writer::spawn(write_db.clone(), write_batch_max);
+        Ok(Shard {
+            db,
+            write_db,
+            writer,
+        })
+    }
 }
 
-#[derive(Serialize)]
-struct DepositResp {
-    stored: bool,
-    msg_id: String,
-    expires_at: i64,
+fn shard_index(mailbox_id: &str, shard_count: usize) -> usize {
+    let digest = Sha256::digest(mailbox_id.as_bytes());
+    let h = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (h % shard_count as u64) as usize
 }
 
-async fn deposit(
-    State(state): State<Arc<AppState>>,
-    Path(mailbox_id): Path<String>,
-    headers: HeaderMap,
-    body: Bytes,
-) -> Result<Json<DepositResp>, ApiError> {
-    if body.len() > state.max_msg_bytes {
-        return Err(ApiError::PayloadTooLarge);
+// Shard files are named after the layout: mailbox.db becomes
+// mailbox.shard-2-of-4.db. A single shard is DATABASE_URL itself. Files are
+// opened with DATABASE_URL's mode, so unless it has `?mode=rwc` a SHARD_COUNT
+// that doesn't match the files on disk fails to start instead of misrouting;
+// with it, the missing files are created empty.
+fn shard_options(base: &SqliteConnectOptions, index: usize, count: usize) -> SqliteConnectOptions {
+    if count == 1 {
+        return base.clone();
     }
+    let path = base.get_filename();
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("mailbox");
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{stem}.shard-{index}-of-{count}.{ext}"),
+        None => format!("{stem}.shard-{index}-of-{count}"),
+    };
+    base.clone().filename(path.with_file_name(name))
+}
 
-    // Auth deposit token
-    let token = bearer_token(&headers)?;
-    let token_raw = b64url_decode(&token)?;
-    if token_raw.len() != 32 {
-        return Err(ApiError::Unauthorized);
+// Tables whose rows belong to one mailbox, parents first.
+// `alias_deposit_tokens` rows follow their alias.
+const SHARDED_TABLES: &[&str] = &[
+    "mailboxes",
+    "deposit_tokens",
+    "messages",
+    "message_hints",
+    "signed_prekeys",
+    "one_time_prekeys",
+    "blobs",
+    "owner_credentials",
+    "mailbox_aliases",
+    "alias_deposit_tokens",
+    "epoch_addresses",
+];
+
+// Offline re-shard from `from` to `to` shard files. The source files are only
+// read; the targets must be new (or empty) and the server must be stopped.
+async fn reshard(base: &SqliteConnectOptions, from: usize, to: usize) -> anyhow::Result<()> {
+    if from == to {
+        anyhow::bail!("already at {to} shard(s)");
     }
-    let dep_hash = hmac_hash(&state.server_secret, &token_raw);
 
-    // mailbox exists?
-    let exists: Option<(String,)> =
-        sqlx::query_as("SELECT mailbox_id FROM mailboxes WHERE mailbox_id = ?")
-            .bind(&mailbox_id)
-            .fetch_optional(&state.db)
-            .await
-            .map_err(|_| ApiError::ServerError)?;
-    if exists.is_none() {
-        return Err(ApiError::NotFound);
+    let mut targets = Vec::with_capacity(to);
+    for index in 0..to {
+        let opts = shard_options(base, index, to).create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(opts.clone())
+            .await?;
+        sqlx::migrate!("./migrations").run(&pool).await?;
+        let (n,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM mailboxes")
+            .fetch_one(&pool)
+            .await?;
+        if n > 0 {
+            anyhow::bail!("target {} is not empty", opts.get_filename().display());
+        }
+        targets.push((opts.get_filename().to_string_lossy().into_owned(), pool));
     }
 
-    // token valid & not revoked?
-    let dep_ok: Option<(i64,)> = sqlx::query_as(
-        "SELECT revoked FROM deposit_tokens WHERE mailbox_id = ? AND dep_hash = ?",
-    )
-    .bind(&mailbox_id)
-    .bind(&dep_hash)
-    .fetch_optional(&state.db)
-    .await
-    .map_err(|_| ApiError::ServerError)?;
-    match dep_ok {
-        Some((revoked,)) if revoked == 0 => {}
-        _ => return Err(ApiError::Forbidden),
+    let mut sources = Vec::with_capacity(from);
+    for index in 0..from {
+        let opts = shard_options(base, index, from).create_if_missing(false);
+        sources.push(
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect_with(opts)
+                .await?,
+        );
+    }
+
+    // Message ids (poll cursors) only need to grow per mailbox. Starting every
+    // target above all source ids means a cursor issued before the re-shard
+    // re-delivers still-queued messages instead of skipping them.
+    let mut max_msg_id: i64 = 0;
+    for source in &sources {
+        let (m,): (i64,) = sqlx::query_as("SELECT COALESCE(MAX(id), 0) FROM messages")
+            .fetch_one(source)
+            .await?;
+        max_msg_id = max_msg_id.max(m);
+    }
+    for (_, pool) in &targets {
+        sqlx::query("INSERT INTO sqlite_sequence (name, seq) VALUES ('messages', ?)")
+            .bind(max_msg_id)
+            .execute(pool)
+            .await?;
+    }
+    let target_paths: Vec<String> = targets.iter().map(|(path, _)| path.clone()).collect();
+    for (_, pool) in targets {
+        pool.close().await;
+    }
+
+    for (index, source) in sources.iter().enumerate() {
+        let mut conn = source.acquire().await?;
+        let ids: Vec<(String,)> = sqlx::query_as("SELECT mailbox_id FROM mailboxes")
+            .fetch_all(&mut *conn)
+            .await?;
+        let mut groups: Vec<Vec<String>> = vec![Vec::new(); to];
+        for (mailbox_id,) in ids {
+            groups[shard_index(&mailbox_id, to)].push(mailbox_id);
+        }
+
+        sqlx::query("CREATE TEMP TABLE IF NOT EXISTS moving (mailbox_id TEXT PRIMARY KEY)")
+            .execute(&mut *conn)
+            .await?;
+        for (target, group) in groups.iter().enumerate() {
+            let copy_invites = index == 0 && target == 0;
+            if group.is_empty() && !copy_invites {
+                continue;
+            }
+            sqlx::query("ATTACH DATABASE ? AS dst")
+                .bind(&target_paths[target])
+                .execute(&mut *conn)
+                .await?;
+
+            let mut tx = conn.begin().await?;
+            sqlx::query("DELETE FROM temp.moving")
+                .execute(&mut *tx)
+                .await?;
+            for mailbox_id in group {
+                sqlx::query("INSERT INTO temp.moving (mailbox_id) VALUES (?)")
+                    .bind(mailbox_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            for table in SHARDED_TABLES {
+                let cols: Vec<String> = sqlx::query(&format!("PRAGMA main.table_info({table})"))
+                    .fetch_all(&mut *tx)
+                    .await?
+                    .iter()
+                    .map(|row| row.try_get::<String, _>("name"))
+                    .collect::<Result<_, _>>()?;
+                let has_id = cols.iter().any(|c| c == "id");
+                let cols = cols
+                    .into_iter()
+                    .filter(|c| c != "id")
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let filter = if *table == "alias_deposit_tokens" {
+                    "alias_id IN (SELECT alias_id FROM main.mailbox_aliases WHERE mailbox_id IN (SELECT mailbox_id FROM temp.moving))"
+                } else {
+                    "mailbox_id IN (SELECT mailbox_id FROM temp.moving)"
+                };
+                let order = if has_id { " ORDER BY id" } else { "" };
+                sqlx::query(&format!(
+                    "INSERT INTO dst.{table} ({cols}) SELECT {cols} FROM main.{table} WHERE {filter}{order}"
+                ))
+                .execute(&mut *tx)
+                .await?;
+            }
+            // Server-wide tables live on shard 0
+            if copy_invites {
+                sqlx::query("INSERT INTO dst.invites SELECT * FROM main.invites")
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+
+            sqlx::query("DETACH DATABASE dst")
+                .execute(&mut *conn)
+                .await?;
+            info!(
+                "moved {} mailbox(es) from shard {}/{} to {}",
+                group.len(),
+                index,
+                from,
+                target_paths[target]
+            );
+        }
+    }
+
+    info!(
+        "re-shard complete; set SHARD_COUNT={} and remove the old files",
+        to
+    );
+    Ok(())
+}
+
+impl AppState {
+    fn shard(&self, mailbox_id: &str) -> &Shard {
+        &self.shards[shard_index(mailbox_id, self.shards.len())]
+    }
+
+    // Server-wide tables (invites)
+    fn directory(&self) -> &Shard {
+        &self.shards[0]
+    }
+}
+
+// Single writer. Deposits, acks and token registrations are queued to one
+// task that applies everything pending in a single transaction (group
+// commit), each job inside its own savepoint so one failing job doesn't
+// abort the batch. Replies are sent once the batch has committed. Other,
+// rarer mutations use `write_db`, which is the same single connection.
+const WRITE_QUEUE_DEPTH: usize = 4096;
+
+struct NewMessage {
+    mailbox_id: String,
+    msg_id: Vec<u8>,
+    blob: Bytes,
+    body_hash: Vec<u8>,
+    dep_hash: Option<Vec<u8>>,
+    sender_sig: Option<Vec<u8>>,
+    alias_id: Option<String>,
+    address: Option<String>,
+    hint: Option<Vec<u8>>,
+    received_at: i64,
+    expires_at: i64,
+    max_queue_messages: i64,
+    max_queue_bytes: i64,
+}
+
+struct NewDepositToken {
+    dep_hash: Vec<u8>,
+    label: Option<Vec<u8>>,
+    sender_pubkey: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy)]
+enum TokenOutcome {
+    Added,
+    Present,
+    Revoked, // registered earlier and revoked; stays revoked
+}
+
+enum WriteJob {
+    // Err(Conflict) means the msg_id already exists
+    Deposit(NewMessage, oneshot::Sender<Result<(), ApiError>>),
+    Ack {
+        mailbox_id: String,
+        msg_ids: Vec<Vec<u8>>,
+        reply: oneshot::Sender<Result<u64, ApiError>>,
+    },
+    RegisterTokens {
+        mailbox_id: String,
+        tokens: Vec<NewDepositToken>,
+        now: i64,
+        reply: oneshot::Sender<Result<Vec<TokenOutcome>, ApiError>>,
+    },
+}
+
+impl WriteJob {
+    fn abort(self) -> PendingReply {
+        match self {
+            WriteJob::Deposit(_, reply) => pending_reply(reply, Err(ApiError::ServerError)),
+            WriteJob::Ack { reply, .. } => pending_reply(reply, Err(ApiError::ServerError)),
+            WriteJob::RegisterTokens { reply, .. } => {
+                pending_reply(reply, Err(ApiError::ServerError))
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Writer {
+    jobs: mpsc::Sender<WriteJob>,
+}
+
+impl Writer {
+    fn spawn(db: Pool<Sqlite>, batch_max: usize) -> Writer {
+        let (jobs, rx) = mpsc::channel(WRITE_QUEUE_DEPTH);
+        tokio::spawn(run_writer(db, rx, batch_max));
+        Writer { jobs }
+    }
+
+    async fn submit<T>(
+        &self,
+        job: impl FnOnce(oneshot::Sender<Result<T, ApiError>>) -> WriteJob,
+    ) -> Result<T, ApiError> {
+        let (reply, rx) = oneshot::channel();
+        self.jobs
+            .send(job(reply))
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+        rx.await.map_err(|_| ApiError::ServerError)?
+    }
+}
+
+// Called with whether the batch committed; a job's result only stands if so.
+type PendingReply = Box<dyn FnOnce(bool) + Send>;
+
+fn pending_reply<T: Send + 'static>(
+    reply: oneshot::Sender<Result<T, ApiError>>,
+    res: Result<T, ApiError>,
+) -> PendingReply {
+    Box::new(move |committed| {
+        let _ = reply.send(if committed {
+            res
+        } else {
+            Err(ApiError::ServerError)
+        });
+    })
+}
+
+async fn run_writer(db: Pool<Sqlite>, mut rx: mpsc::Receiver<WriteJob>, batch_max: usize) {
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < batch_max {
+            match rx.try_recv() {
+                Ok(job) => batch.push(job),
+                Err(_) => break,
+            }
+        }
+
+        let mut replies: Vec<PendingReply> = Vec::with_capacity(batch.len());
+        let committed = match db.begin().await {
+            Ok(mut tx) => {
+                for job in batch {
+                    replies.push(apply_write(&mut tx, job).await);
+                }
+                tx.commit()
+                    .await
+                    .inspect_err(|e| tracing::warn!(error = %e, "group commit failed"))
+                    .is_ok()
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "writer could not begin a transaction");
+                replies.extend(batch.into_iter().map(WriteJob::abort));
+                false
+            }
+        };
+        for reply in replies {
+            reply(committed);
+        }
+    }
+}
+
+async fn apply_write(tx: &mut Transaction<'_, Sqlite>, job: WriteJob) -> PendingReply {
+    match job {
+        WriteJob::Deposit(m, reply) => pending_reply(reply, write_message(tx, m).await),
+        WriteJob::Ack {
+            mailbox_id,
+            msg_ids,
+            reply,
+        } => pending_reply(reply, write_ack(tx, &mailbox_id, msg_ids).await),
+        WriteJob::RegisterTokens {
+            mailbox_id,
+            tokens,
+            now,
+            reply,
+        } => pending_reply(
+            reply,
+            write_deposit_tokens(tx, &mailbox_id, tokens, now).await,
+        ),
+    }
+}
+
+async fn write_message(conn: &mut SqliteConnection, m: NewMessage) -> Result<(), ApiError> {
+    let mut sp = conn.begin().await.map_err(|_| ApiError::ServerError)?;
+
+    // Reserve queue space; the conditional update enforces the quota atomically
+    let size = m.blob.len() as i64;
+    let reserved = sqlx::query(
+        "UPDATE mailboxes SET queued_count = queued_count + 1, queued_bytes = queued_bytes + ? WHERE mailbox_id = ? AND queued_count + 1 <= ? AND queued_bytes + ? <= ?",
+    )
+    .bind(size)
+    .bind(&m.mailbox_id)
+    .bind(m.max_queue_messages)
+    .bind(size)
+    .bind(m.max_queue_bytes)
+    .execute(&mut *sp)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    if reserved.rows_affected() == 0 {
+        let (queued_count,): (i64,) =
+            sqlx::query_as("SELECT queued_count FROM mailboxes WHERE mailbox_id = ?")
+                .bind(&m.mailbox_id)
+                .fetch_one(&mut *sp)
+                .await
+                .map_err(|_| ApiError::ServerError)?;
+        if queued_count + 1 > m.max_queue_messages {
+            return Err(ApiError::QuotaExceeded("queue_messages"));
+        }
+        return Err(ApiError::QuotaExceeded("queue_bytes"));
+    }
+
+    // Insert with idempotence
+    let res = sqlx::query(
+        "INSERT INTO messages (mailbox_id, msg_id, blob, body_hash, dep_hash, sender_sig, alias_id, address, received_at, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&m.mailbox_id)
+    .bind(&m.msg_id)
+    .bind(m.blob.as_ref())
+    .bind(&m.body_hash)
+    .bind(&m.dep_hash)
+    .bind(&m.sender_sig)
+    .bind(&m.alias_id)
+    .bind(&m.address)
+    .bind(m.received_at)
+    .bind(m.expires_at)
+    .execute(&mut *sp)
+    .await;
+    if let Err(e) = res {
+        if format!("{e}").to_lowercase().contains("unique") {
+            return Err(ApiError::Conflict);
+        }
+        return Err(ApiError::ServerError);
+    }
+    if let Some(hint) = &m.hint {
+        sqlx::query("INSERT INTO message_hints (mailbox_id, msg_id, hint) VALUES (?, ?, ?)")
+            .bind(&m.mailbox_id)
+            .bind(&m.msg_id)
+            .bind(hint)
+            .execute(&mut *sp)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+    }
+
+    sp.commit().await.map_err(|_| ApiError::ServerError)
+}
+
+async fn write_ack(
+    conn: &mut SqliteConnection,
+    mailbox_id: &str,
+    msg_ids: Vec<Vec<u8>>,
+) -> Result<u64, ApiError> {
+    let mut sp = conn.begin().await.map_err(|_| ApiError::ServerError)?;
+    let mut deleted: i64 = 0;
+    let mut deleted_bytes: i64 = 0;
+    for raw in msg_ids {
+        let row: Option<(i64,)> = sqlx::query_as(
+            "DELETE FROM messages WHERE mailbox_id = ? AND msg_id = ? RETURNING LENGTH(blob)",
+        )
+        .bind(mailbox_id)
+        .bind(raw)
+        .fetch_optional(&mut *sp)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+        if let Some((bytes,)) = row {
+            deleted += 1;
+            deleted_bytes += bytes;
+        }
+    }
+    release_queued(&mut sp, mailbox_id, deleted, deleted_bytes)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    sp.commit().await.map_err(|_| ApiError::ServerError)?;
+    Ok(deleted as u64)
+}
+
+// One outcome per token, in order. Set-based: a single lookup of the tokens
+// already registered, then a single multi-row upsert for the rest.
+async fn write_deposit_tokens(
+    conn: &mut SqliteConnection,
+    mailbox_id: &str,
+    tokens: Vec<NewDepositToken>,
+    now: i64,
+) -> Result<Vec<TokenOutcome>, ApiError> {
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut sp = conn.begin().await.map_err(|_| ApiError::ServerError)?;
+
+    let mut lookup = QueryBuilder::<Sqlite>::new(
+        "SELECT dep_hash, revoked FROM deposit_tokens WHERE mailbox_id = ",
+    );
+    lookup.push_bind(mailbox_id).push(" AND dep_hash IN (");
+    let mut hashes = lookup.separated(", ");
+    for t in &tokens {
+        hashes.push_bind(&t.dep_hash);
+    }
+    lookup.push(")");
+    let rows: Vec<(Vec<u8>, i64)> = lookup
+        .build_query_as()
+        .fetch_all(&mut *sp)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    let mut revoked: HashMap<Vec<u8>, bool> = rows.into_iter().map(|(h, r)| (h, r != 0)).collect();
+
+    // Re-registering a known token updates the label / sender key given
+    let mut outcomes = Vec::with_capacity(tokens.len());
+    let mut upserts = Vec::new();
+    for t in &tokens {
+        let outcome = match revoked.get(&t.dep_hash) {
+            Some(true) => TokenOutcome::Revoked,
+            Some(false) => {
+                if t.label.is_some() || t.sender_pubkey.is_some() {
+                    upserts.push(t);
+                }
+                TokenOutcome::Present
+            }
+            None => {
+                revoked.insert(t.dep_hash.clone(), false);
+                upserts.push(t);
+                TokenOutcome::Added
+            }
+        };
+        outcomes.push(outcome);
+    }
+
+    if !upserts.is_empty() {
+        let mut insert = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO deposit_tokens (mailbox_id, dep_hash, revoked, created_at, label, sender_pubkey) ",
+        );
+        insert.push_values(upserts, |mut row, t| {
+            row.push_bind(mailbox_id)
+                .push_bind(&t.dep_hash)
+                .push("0")
+                .push_bind(now)
+                .push_bind(&t.label)
+                .push_bind(&t.sender_pubkey);
+        });
+        insert.push(
+            " ON CONFLICT (mailbox_id, dep_hash) DO UPDATE SET label = COALESCE(excluded.label, label), sender_pubkey = COALESCE(excluded.sender_pubkey, sender_pubkey)",
+        );
+        insert
+            .build()
+            .execute(&mut *sp)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+    }
+    sp.commit().await.map_err(|_| ApiError::ServerError)?;
+    Ok(outcomes)
+}
+
+// Subtracts deleted messages from a mailbox's queue counters. Must run in
+// the transaction that deleted them.
+async fn release_queued(
+    conn: &mut SqliteConnection,
+    mailbox_id: &str,
+    count: i64,
+    bytes: i64,
+) -> Result<(), sqlx::Error> {
+    if count == 0 {
+        return Ok(());
+    }
+    sqlx::query(
+        "UPDATE mailboxes SET queued_count = queued_count - ?, queued_bytes = queued_bytes - ? WHERE mailbox_id = ?",
+    )
+    .bind(count)
+    .bind(bytes)
+    .bind(mailbox_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn purge_expired_messages(db: &Pool<Sqlite>, now: i64) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let rows = sqlx::query(
+        "DELETE FROM messages WHERE expires_at <= ? RETURNING mailbox_id, LENGTH(blob) AS bytes",
+    )
+    .bind(now)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut released: HashMap<String, (i64, i64)> = HashMap::new();
+    for row in rows {
+        let entry = released.entry(row.try_get("mailbox_id")?).or_default();
+        entry.0 += 1;
+        entry.1 += row.try_get::<i64, _>("bytes")?;
+    }
+    for (mailbox_id, (count, bytes)) in released {
+        release_queued(&mut tx, &mailbox_id, count, bytes).await?;
+    }
+    tx.commit().await
+}
+
+// Recomputes the queue counters from the messages; returns how many
+// mailboxes had drifted.
+async fn recount_queues(db: &Pool<Sqlite>) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query(
+        r#"
+        UPDATE mailboxes SET queued_bytes = q.bytes, queued_count = q.count
+        FROM (
+            SELECT mb.mailbox_id AS mailbox_id,
+                   COALESCE(SUM(LENGTH(m.blob)), 0) AS bytes,
+                   COUNT(m.msg_id) AS count
+            FROM mailboxes mb LEFT JOIN messages m ON m.mailbox_id = mb.mailbox_id
+            GROUP BY mb.mailbox_id
+        ) AS q
+        WHERE mailboxes.mailbox_id = q.mailbox_id
+          AND (mailboxes.queued_bytes != q.bytes OR mailboxes.queued_count != q.count)
+        "#,
+    )
+    .execute(db)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+// Materialises the previous, current and next epoch address of rotating
+// mailboxes (all of them, or just `only`) and drops expired ones. Address e
+// is accepted from the start of epoch e-1 to the end of epoch e+1.
+async fn refresh_epoch_addresses(
+    db: &Pool<Sqlite>,
+    only: Option<&str>,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    let rows: Vec<(String, Vec<u8>, i64)> = sqlx::query_as(
+        r#"
+        SELECT mailbox_id, rotation_secret, rotation_epoch_secs
+        FROM mailboxes
+        WHERE rotation_secret IS NOT NULL AND (? IS NULL OR mailbox_id = ?)
+        "#,
+    )
+    .bind(only)
+    .bind(only)
+    .fetch_all(db)
+    .await?;
+
+    let mut tx = db.begin().await?;
+    for (mailbox_id, secret, epoch_secs) in rows {
+        let current = now / epoch_secs;
+        for epoch in current - 1..=current + 1 {
+            sqlx::query(
+                "INSERT OR IGNORE INTO epoch_addresses (address, mailbox_id, epoch, not_before, not_after) VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(epoch_address(&secret, epoch))
+            .bind(&mailbox_id)
+            .bind(epoch)
+            .bind((epoch - 1) * epoch_secs)
+            .bind((epoch + 2) * epoch_secs)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    sqlx::query("DELETE FROM epoch_addresses WHERE not_after <= ?")
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+// Checks the bearer deposit token is registered and not revoked for the
+// addressed mailbox (and allowed on the alias, if restricted), or that a
+// macaroon allows depositing `body_len` bytes.
+async fn auth_depositor(
+    state: &AppState,
+    address: &str,
+    headers: &HeaderMap,
+    body_len: usize,
+) -> Result<Depositor, ApiError> {
+    let started = Instant::now();
+    match check_depositor(state, address, headers, body_len).await {
+        Ok(d) => Ok(d),
+        Err(e) => Err(state.auth_failure(e, started).await),
+    }
+}
+
+async fn check_depositor(
+    state: &AppState,
+    address: &str,
+    headers: &HeaderMap,
+    body_len: usize,
+) -> Result<Depositor, ApiError> {
+    let (mailbox_id, alias_id) = resolve_address(state, address).await?;
+
+    // Tokens an alias is restricted to (empty = any token of the mailbox)
+    let alias_tokens: Vec<Vec<u8>> = match &alias_id {
+        Some(a) => {
+            let key = AuthKey::AliasTokens(a.clone());
+            match state.auth_cache.get(&key) {
+                Some(AuthEntry::AliasTokens(tokens)) => tokens,
+                _ => {
+                    let generation = state.auth_cache.generation();
+                    let rows: Vec<(Vec<u8>,)> = sqlx::query_as(
+                        "SELECT dep_hash FROM alias_deposit_tokens WHERE alias_id = ?",
+                    )
+                    .bind(a)
+                    .fetch_all(&state.shard(&mailbox_id).db)
+                    .await
+                    .map_err(|_| ApiError::ServerError)?;
+                    let tokens: Vec<Vec<u8>> = rows.into_iter().map(|(h,)| h).collect();
+                    state
+                        .auth_cache
+                        .put(key, AuthEntry::AliasTokens(tokens.clone()), generation);
+                    tokens
+                }
+            }
+        }
+        None => Vec::new(),
+    };
+
+    if let Some(m) = macaroon_token(headers) {
+        if !alias_tokens.is_empty() {
+            return Err(ApiError::Forbidden);
+        }
+        verify_macaroon(state, &mailbox_id, m, Op::Deposit, body_len).await?;
+        return Ok(Depositor {
+            mailbox_id,
+            alias_id,
+            dep_hash: None,
+            sender_pubkey: None,
+        });
+    }
+
+    let token = bearer_token(headers)?;
+    let token_raw = b64url_decode(&token)?;
+    if token_raw.len() != 32 {
+        return Err(ApiError::Unauthorized);
+    }
+    let dep_hash = hmac_hash(&state.server_secret, &token_raw);
+
+    if !alias_tokens.is_empty() && !alias_tokens.iter().any(|h| ct_eq(h, &dep_hash)) {
+        return Err(ApiError::Forbidden);
+    }
+
+    let key = AuthKey::Deposit(mailbox_id.clone(), dep_hash.clone());
+    if let Some(AuthEntry::Deposit(sender_pubkey)) = state.auth_cache.get(&key) {
+        return Ok(Depositor {
+            mailbox_id,
+            alias_id,
+            dep_hash: Some(dep_hash),
+            sender_pubkey,
+        });
+    }
+
+    // token valid & not revoked?
+    let generation = state.auth_cache.generation();
+    let dep_ok: Option<(i64, Option<Vec<u8>>)> = sqlx::query_as(
+        "SELECT revoked, sender_pubkey FROM deposit_tokens WHERE mailbox_id = ? AND dep_hash = ?",
+    )
+    .bind(&mailbox_id)
+    .bind(&dep_hash)
+    .fetch_optional(&state.shard(&mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    match dep_ok {
+        Some((0, sender_pubkey)) => {
+            let entry = AuthEntry::Deposit(sender_pubkey.clone());
+            state.auth_cache.put(key, entry, generation);
+            Ok(Depositor {
+                mailbox_id,
+                alias_id,
+                dep_hash: Some(dep_hash),
+                sender_pubkey,
+            })
+        }
+        _ => Err(ApiError::Forbidden),
+    }
+}
+
+// Macaroons: `Authorization: Macaroon base64url(json)` where the JSON is
+// `{"id": b64url, "caveats": [..], "sig": b64url}` and
+//   sig = HMAC(...HMAC(HMAC(root_key, id), caveat_1)..., caveat_n)
+// Anyone holding a macaroon can append a caveat and re-chain the signature
+// offline, which only ever narrows what it grants. Caveats:
+//   ops=poll,ack,manage,deposit   allowed operations
+//   expires=<unix ts>             invalid from then on
+//   max_msg_bytes=<n>             largest deposit body
+//   deposit_until=<unix ts>       no deposits from then on
+#[derive(Deserialize, Serialize)]
+struct Macaroon {
+    id: String,
+    caveats: Vec<String>,
+    sig: String,
+}
+
+const MAX_MACAROON_CAVEATS: usize = 32;
+
+fn macaroon_token(headers: &HeaderMap) -> Option<&str> {
+    let auth = headers.get("authorization")?.to_str().ok()?;
+    let (scheme, token) = auth.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("macaroon")
+        .then(|| token.trim())
+}
+
+fn macaroon_root_key(server_secret: &[u8], mailbox_id: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(server_secret).expect("HMAC key");
+    mac.update(b"macaroon");
+    mac.update(mailbox_id.as_bytes());
+    mac.update(nonce);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn macaroon_chain(root_key: &[u8], id: &[u8], caveats: &[String]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(root_key).expect("HMAC key");
+    mac.update(id);
+    for c in caveats {
+        let tag = mac.finalize().into_bytes();
+        mac = HmacSha256::new_from_slice(&tag).expect("HMAC key");
+        mac.update(c.as_bytes());
+    }
+    mac
+}
+
+// Parses a caveat and checks it holds for this request. Unknown caveats fail.
+fn check_caveat(caveat: &str, op: Op, now: i64, body_len: usize) -> Result<bool, ApiError> {
+    let (key, value) = caveat.split_once('=').ok_or(ApiError::InvalidInput)?;
+    let num = || value.parse::<i64>().map_err(|_| ApiError::InvalidInput);
+    Ok(match key {
+        "ops" => {
+            let mut allowed = false;
+            for o in value.split(',') {
+                allowed |= Op::parse(o).ok_or(ApiError::InvalidInput)? == op;
+            }
+            allowed
+        }
+        "expires" => now < num()?,
+        "max_msg_bytes" => op != Op::Deposit || (body_len as i64) <= num()?,
+        "deposit_until" => op != Op::Deposit || now < num()?,
+        _ => return Err(ApiError::InvalidInput),
+    })
+}
+
+// Whether a macaroon with valid signature and these caveats grants `op`.
+// `Admin` never is: otherwise a narrowed macaroon could mint a fresh
+// unrestricted one (or a credential) and undo its own attenuation.
+fn macaroon_permits(caveats: &[String], op: Op, now: i64, body_len: usize) -> bool {
+    op != Op::Admin
+        && caveats
+            .iter()
+            .all(|c| matches!(check_caveat(c, op, now, body_len), Ok(true)))
+}
+
+async fn verify_macaroon(
+    state: &AppState,
+    mailbox_id: &str,
+    token: &str,
+    op: Op,
+    body_len: usize,
+) -> Result<(), ApiError> {
+    let m: Macaroon =
+        serde_json::from_slice(&b64url_decode(token)?).map_err(|_| ApiError::Unauthorized)?;
+    if m.caveats.len() > MAX_MACAROON_CAVEATS {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let mb: Option<(Option<Vec<u8>>,)> =
+        sqlx::query_as("SELECT macaroon_nonce FROM mailboxes WHERE mailbox_id = ?")
+            .bind(mailbox_id)
+            .fetch_optional(&state.shard(mailbox_id).db)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+    let Some((nonce,)) = mb else {
+        return Err(ApiError::NotFound);
+    };
+    let nonce = nonce.ok_or(ApiError::Forbidden)?;
+
+    let root_key = macaroon_root_key(&state.server_secret, mailbox_id, &nonce);
+    macaroon_chain(&root_key, &b64url_decode(&m.id)?, &m.caveats)
+        .verify_slice(&b64url_decode(&m.sig)?)
+        .map_err(|_| ApiError::Forbidden)?;
+
+    if !macaroon_permits(&m.caveats, op, unix_ts(), body_len) {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(())
+}
+
+// Marks a request whose owner signature was verified for `mailbox_id`.
+#[derive(Clone)]
+struct SignedOwner {
+    mailbox_id: String,
+}
+
+// Bodies of signed owner requests are buffered to hash them.
+const SIGNED_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+// Verifies `X-Whisper-Owner-Signature` when present. The signature is
+// Ed25519 by the mailbox's owner key over:
+//   "whisper-owner-v1\n" METHOD "\n" path?query "\n" hex(sha256(body)) "\n" timestamp "\n" nonce
+// Requests without the header pass through to bearer auth.
+async fn verify_owner_signature(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if !req.headers().contains_key("x-whisper-owner-signature") {
+        return Ok(next.run(req).await);
+    }
+
+    let started = Instant::now();
+    match check_owner_signature(&state, req).await {
+        Ok(req) => Ok(next.run(req).await),
+        Err(e) => Err(state.auth_failure(e, started).await),
+    }
+}
+
+// Returns the request, body restored, tagged with `SignedOwner`.
+async fn check_owner_signature(state: &Arc<AppState>, req: Request) -> Result<Request, ApiError> {
+    let (mut parts, body) = req.into_parts();
+    let mailbox_id = RawPathParams::from_request_parts(&mut parts, state)
+        .await
+        .ok()
+        .and_then(|params| {
+            params
+                .iter()
+                .find(|(k, _)| *k == "mailbox_id")
+                .map(|(_, v)| v.to_string())
+        })
+        .ok_or(ApiError::Unauthorized)?;
+    let body = axum::body::to_bytes(body, SIGNED_BODY_LIMIT)
+        .await
+        .map_err(|_| ApiError::PayloadTooLarge)?;
+
+    let header = |name: &str| -> Result<&str, ApiError> {
+        parts
+            .headers
+            .get(name)
+            .ok_or(ApiError::Unauthorized)?
+            .to_str()
+            .map_err(|_| ApiError::Unauthorized)
+    };
+    let signature = b64url_decode(header("x-whisper-owner-signature")?)
+        .ok()
+        .and_then(|raw| Signature::from_slice(&raw).ok())
+        .ok_or(ApiError::Unauthorized)?;
+    let timestamp: i64 = header("x-whisper-owner-timestamp")?
+        .parse()
+        .map_err(|_| ApiError::Unauthorized)?;
+    let nonce = header("x-whisper-owner-nonce")?;
+    if !(16..=32).contains(&b64url_decode(nonce)?.len()) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let mb: Option<(Option<Vec<u8>>,)> =
+        sqlx::query_as("SELECT owner_pubkey FROM mailboxes WHERE mailbox_id = ?")
+            .bind(&mailbox_id)
+            .fetch_optional(&state.shard(&mailbox_id).db)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+    let Some((owner_pubkey,)) = mb else {
+        return Err(ApiError::NotFound);
+    };
+    let owner_key = owner_pubkey
+        .as_deref()
+        .and_then(|k| <[u8; 32]>::try_from(k).ok())
+        .and_then(|k| VerifyingKey::from_bytes(&k).ok())
+        .ok_or(ApiError::Forbidden)?;
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let signed_msg = format!(
+        "whisper-owner-v1\n{}\n{}\n{}\n{}\n{}",
+        parts.method.as_str(),
+        path,
+        hex::encode(Sha256::digest(&body)),
+        timestamp,
+        nonce
+    );
+    owner_key
+        .verify_strict(signed_msg.as_bytes(), &signature)
+        .map_err(|_| ApiError::Forbidden)?;
+
+    spend_nonce(
+        &state.owner_nonces,
+        format!("{mailbox_id}:{nonce}"),
+        timestamp,
+        state.owner_sig_max_skew_secs,
+        unix_ts(),
+    )?;
+
+    parts.extensions.insert(SignedOwner { mailbox_id });
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+const MAX_OWNER_NONCES: usize = 100_000;
+
+// Replay protection for signed requests: the timestamp must be within `skew`
+// of now, and each nonce is accepted once while it is. A nonce is remembered
+// through `timestamp + skew`, the last second its request is still accepted.
+fn spend_nonce(
+    nonces: &Mutex<HashMap<String, i64>>,
+    key: String,
+    timestamp: i64,
+    skew: i64,
+    now: i64,
+) -> Result<(), ApiError> {
+    if (now - timestamp).abs() > skew {
+        return Err(ApiError::Unauthorized);
+    }
+    let mut nonces = nonces.lock().unwrap();
+    if nonces.len() >= MAX_OWNER_NONCES {
+        nonces.retain(|_, last_valid| *last_valid >= now);
+    }
+    if nonces.len() >= MAX_OWNER_NONCES {
+        return Err(ApiError::RateLimited);
+    }
+    if nonces
+        .get(&key)
+        .is_some_and(|last_valid| *last_valid >= now)
+    {
+        return Err(ApiError::Unauthorized);
+    }
+    nonces.insert(key, timestamp + skew);
+    Ok(())
+}
+
+fn decode_ed25519_key(s: &str) -> Result<VerifyingKey, ApiError> {
+    let raw = b64url_decode(s)?;
+    let key = <[u8; 32]>::try_from(raw.as_slice()).map_err(|_| ApiError::InvalidInput)?;
+    VerifyingKey::from_bytes(&key).map_err(|_| ApiError::InvalidInput)
+}
+
+fn hmac_hash(server_secret: &[u8], token: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(server_secret).expect("HMAC key");
+    mac.update(token);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn b64url_decode(s: &str) -> Result<Vec<u8>, ApiError> {
+    URL_SAFE_NO_PAD
+        .decode(s.as_bytes())
+        .map_err(|_| ApiError::InvalidInput)
+}
+fn b64url_encode(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn random_b64url(nbytes: usize) -> String {
+    let mut buf = vec![0u8; nbytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    b64url_encode(&buf)
+}
+
+// Cursor = base64url( last_id (8 bytes LE) || mac(32 bytes) )
+// mac = HMAC(server_secret, "cursor" || mailbox_id || last_id_bytes)
+fn cursor_encode(server_secret: &[u8], mailbox_id: &str, last_id: i64) -> String {
+    let last = (last_id as u64).to_le_bytes();
+    let mut mac = HmacSha256::new_from_slice(server_secret).unwrap();
+    mac.update(b"cursor");
+    mac.update(mailbox_id.as_bytes());
+    mac.update(&last);
+    let tag = mac.finalize().into_bytes();
+
+    let mut out = Vec::with_capacity(8 + 32);
+    out.extend_from_slice(&last);
+    out.extend_from_slice(&tag);
+    b64url_encode(&out)
+}
+
+fn cursor_decode(server_secret: &[u8], mailbox_id: &str, cursor: &str) -> Result<i64, ApiError> {
+    let raw = b64url_decode(cursor)?;
+    if raw.len() != 40 {
+        return Err(ApiError::InvalidInput);
+    }
+    let (last_bytes, tag) = raw.split_at(8);
+
+    let mut mac = HmacSha256::new_from_slice(server_secret).unwrap();
+    mac.update(b"cursor");
+    mac.update(mailbox_id.as_bytes());
+    mac.update(last_bytes);
+    mac.verify_slice(tag).map_err(|_| ApiError::InvalidInput)?;
+
+    let mut arr = [0u8; 8];
+    arr.copy_from_slice(last_bytes);
+    let last_id = u64::from_le_bytes(arr) as i64;
+    Ok(last_id)
+}
+
+#[derive(Deserialize)]
+struct CreateMailboxReq {
+    poll_token: Option<String>,
+    owner_pubkey: Option<String>, // base64url(32 bytes), Ed25519
+    invite_code: Option<String>,  // required when REQUIRE_INVITE is set
+    pow: Option<PowSolution>,     // required when POW_DIFFICULTY_BITS > 0
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(into = "LimitsResp")]
+struct Limits {
+    max_msg_bytes: usize,
+    max_queue_bytes: i64,
+    max_queue_messages: i64,
+    ttl_secs: i64, // default TTL
+    max_ttl_secs: i64,
+}
+
+// Limits as returned, with the older whole-day TTL fields (rounded down)
+// next to the second-based ones.
+#[derive(Serialize)]
+struct LimitsResp {
+    max_msg_bytes: usize,
+    max_queue_bytes: i64,
+    max_queue_messages: i64,
+    ttl_secs: i64,
+    max_ttl_secs: i64,
+    ttl_days: i64,
+    max_ttl_days: i64,
+}
+
+impl From<Limits> for LimitsResp {
+    fn from(l: Limits) -> LimitsResp {
+        LimitsResp {
+            max_msg_bytes: l.max_msg_bytes,
+            max_queue_bytes: l.max_queue_bytes,
+            max_queue_messages: l.max_queue_messages,
+            ttl_secs: l.ttl_secs,
+            max_ttl_secs: l.max_ttl_secs,
+            ttl_days: l.ttl_secs / 86_400,
+            max_ttl_days: l.max_ttl_secs / 86_400,
+        }
+    }
+}
+
+// A TTL given in seconds, or in whole days through a deprecated field
+fn ttl_secs_or_days(secs: Option<i64>, days: Option<i64>) -> Option<i64> {
+    secs.or(days.map(|d| d.saturating_mul(86_400)))
+}
+
+// A quota tier from QUOTA_TIERS; unset fields fall back to the server defaults.
+// Unknown fields fail startup instead of being ignored.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct TierLimits {
+    max_msg_bytes: Option<usize>,
+    max_queue_bytes: Option<i64>,
+    max_queue_messages: Option<i64>,
+    ttl_secs: Option<i64>,
+    max_ttl_secs: Option<i64>,
+    ttl_days: Option<i64>,     // deprecated, used if ttl_secs is unset
+    max_ttl_days: Option<i64>, // deprecated, used if max_ttl_secs is unset
+}
+
+impl AppState {
+    // Allowance for a new mailbox of `tier` (server defaults if none/unknown).
+    fn tier_limits(&self, tier: Option<&str>) -> Limits {
+        let t = tier.and_then(|t| self.tiers.get(t));
+        Limits {
+            max_msg_bytes: t
+                .and_then(|t| t.max_msg_bytes)
+                .unwrap_or(self.max_msg_bytes),
+            max_queue_bytes: t
+                .and_then(|t| t.max_queue_bytes)
+                .unwrap_or(self.max_queue_bytes),
+            max_queue_messages: t
+                .and_then(|t| t.max_queue_messages)
+                .unwrap_or(self.max_queue_messages),
+            ttl_secs: t
+                .and_then(|t| ttl_secs_or_days(t.ttl_secs, t.ttl_days))
+                .unwrap_or(self.default_ttl_secs),
+            max_ttl_secs: t
+                .and_then(|t| ttl_secs_or_days(t.max_ttl_secs, t.max_ttl_days))
+                .unwrap_or(self.max_ttl_secs),
+        }
+    }
+
+    // Largest deposit any mailbox may accept; checked before authenticating.
+    fn largest_msg_bytes(&self) -> usize {
+        self.tiers
+            .values()
+            .filter_map(|t| t.max_msg_bytes)
+            .fold(self.max_msg_bytes, usize::max)
+    }
+}
+
+// Returns (allowance, effective limits) for an existing mailbox.
+async fn mailbox_limits(state: &AppState, mailbox_id: &str) -> Result<(Limits, Limits), ApiError> {
+    let key = AuthKey::Limits(mailbox_id.to_string());
+    if let Some(AuthEntry::Limits(allowed, effective)) = state.auth_cache.get(&key) {
+        return Ok((allowed, effective));
+    }
+
+    let generation = state.auth_cache.generation();
+    let row = sqlx::query(
+        "SELECT max_msg_bytes, max_queue_bytes, max_queue_messages, ttl_secs, max_ttl_secs, owner_max_msg_bytes, owner_max_queue_bytes, owner_max_queue_messages, owner_ttl_secs, owner_max_ttl_secs FROM mailboxes WHERE mailbox_id = ?",
+    )
+    .bind(mailbox_id)
+    .fetch_optional(&state.shard(mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?
+    .ok_or(ApiError::NotFound)?;
+    let col = |name: &str| -> Result<Option<i64>, ApiError> {
+        row.try_get(name).map_err(|_| ApiError::ServerError)
+    };
+
+    let defaults = state.tier_limits(None);
+    let allowed = Limits {
+        max_msg_bytes: col("max_msg_bytes")?.map_or(defaults.max_msg_bytes, |v| v as usize),
+        max_queue_bytes: col("max_queue_bytes")?.unwrap_or(defaults.max_queue_bytes),
+        max_queue_messages: col("max_queue_messages")?.unwrap_or(defaults.max_queue_messages),
+        ttl_secs: col("ttl_secs")?.unwrap_or(defaults.ttl_secs),
+        max_ttl_secs: col("max_ttl_secs")?.unwrap_or(defaults.max_ttl_secs),
+    };
+    let max_ttl_secs =
+        col("owner_max_ttl_secs")?.map_or(allowed.max_ttl_secs, |v| v.min(allowed.max_ttl_secs));
+    let effective = Limits {
+        max_msg_bytes: col("owner_max_msg_bytes")?.map_or(allowed.max_msg_bytes, |v| {
+            (v as usize).min(allowed.max_msg_bytes)
+        }),
+        max_queue_bytes: col("owner_max_queue_bytes")?
+            .map_or(allowed.max_queue_bytes, |v| v.min(allowed.max_queue_bytes)),
+        max_queue_messages: col("owner_max_queue_messages")?
+            .map_or(allowed.max_queue_messages, |v| {
+                v.min(allowed.max_queue_messages)
+            }),
+        ttl_secs: col("owner_ttl_secs")?
+            .map_or(allowed.ttl_secs, |v| v.min(allowed.ttl_secs))
+            .min(max_ttl_secs),
+        max_ttl_secs,
+    };
+    state
+        .auth_cache
+        .put(key, AuthEntry::Limits(allowed, effective), generation);
+    Ok((allowed, effective))
+}
+
+#[derive(Serialize)]
+struct CreateMailboxResp {
+    mailbox_id: String,
+    poll_token: Option<String>,
+    tier: Option<String>,
+    limits: Limits,
+}
+
+async fn create_mailbox(
+    State(state): State<Arc<AppState>>,
+    Payload(req): Payload<CreateMailboxReq>,
+) -> Result<Json<CreateMailboxResp>, ApiError> {
+    let now = unix_ts();
+
+    // Admission: proof-of-work is checked before touching the database
+    let challenge = match (&req.pow, state.pow_difficulty_bits) {
+        (_, 0) => None,
+        (Some(pow), _) => Some(check_pow(&state, pow, now)?),
+        (None, _) => return Err(ApiError::Forbidden),
+    };
+    let res = insert_mailbox(&state, req, now).await;
+    // The challenge is only used up once it has admitted a mailbox, so a bad
+    // invite or a failed insert doesn't burn the solved work
+    if let (Err(_), Some(challenge)) = (&res, challenge) {
+        state.pow_spent.lock().unwrap().remove(&challenge);
+    }
+    res.map(Json)
+}
+
+// Spends the invite, if any, and inserts the mailbox
+async fn insert_mailbox(
+    state: &AppState,
+    req: CreateMailboxReq,
+    now: i64,
+) -> Result<CreateMailboxResp, ApiError> {
+    let mailbox_id = random_b64url(24);
+    let invite_hash = match (&req.invite_code, state.require_invite) {
+        (Some(code), _) => Some(hmac_hash(&state.server_secret, &b64url_decode(code)?)),
+        (None, true) => return Err(ApiError::Forbidden),
+        (None, false) => None,
+    };
+
+    let owner_pubkey = match req.owner_pubkey {
+        Some(k) => Some(decode_ed25519_key(&k)?.to_bytes().to_vec()),
+        None => None,
+    };
+
+    let (poll_token, poll_hash) = match req.poll_token {
+        Some(t) => {
+            let raw = b64url_decode(&t)?;
+            if raw.len() != 32 {
+                return Err(ApiError::InvalidInput);
+            }
+            (None, hmac_hash(&state.server_secret, &raw))
+        }
+        None => {
+            let mut raw = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut raw);
+            // Key-bound mailboxes get no bearer token: the random value is
+            // hashed and discarded, so only signed requests can act as owner.
+            let token_b64 = owner_pubkey.is_none().then(|| b64url_encode(&raw));
+            (token_b64, hmac_hash(&state.server_secret, &raw))
+        }
+    };
+
+    // Spend one use of the invite. Invites live on the directory shard, which
+    // needn't be the mailbox's, so the use is refunded if the insert fails.
+    let mut tier: Option<String> = None;
+    if let Some(invite_hash) = &invite_hash {
+        let row: Option<(Option<String>,)> = sqlx::query_as(
+            "UPDATE invites SET uses_left = uses_left - 1 WHERE code_hash = ? AND uses_left > 0 AND (expires_at IS NULL OR expires_at > ?) RETURNING tier",
+        )
+        .bind(invite_hash)
+        .bind(now)
+        .fetch_optional(&state.directory().write_db)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+        let Some((t,)) = row else {
+            return Err(ApiError::Forbidden);
+        };
+        tier = t;
+    }
+
+    let limits = state.tier_limits(tier.as_deref());
+    let res = sqlx::query(
+        "INSERT INTO mailboxes (mailbox_id, poll_hash, owner_pubkey, tier, max_msg_bytes, max_queue_bytes, max_queue_messages, ttl_secs, max_ttl_secs, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&mailbox_id)
+    .bind(poll_hash)
+    .bind(owner_pubkey)
+    .bind(&tier)
+    .bind(limits.max_msg_bytes as i64)
+    .bind(limits.max_queue_bytes)
+    .bind(limits.max_queue_messages)
+    .bind(limits.ttl_secs)
+    .bind(limits.max_ttl_secs)
+    .bind(now)
+    .execute(&state.shard(&mailbox_id).write_db)
+    .await;
+    if res.is_err() {
+        if let Some(invite_hash) = &invite_hash {
+            let _ = sqlx::query("UPDATE invites SET uses_left = uses_left + 1 WHERE code_hash = ?")
+                .bind(invite_hash)
+                .execute(&state.directory().write_db)
+                .await;
+        }
+        return Err(ApiError::ServerError);
+    }
+
+    Ok(CreateMailboxResp {
+        mailbox_id,
+        poll_token,
+        tier,
+        limits,
+    })
+}
+
+#[derive(Deserialize)]
+struct RegisterDepositTokensReq {
+    deposit_tokens: Vec<DepositTokenEntry>,
+}
+
+// Opaque label size cap (decoded bytes). The server never interprets labels.
+const MAX_LABEL_BYTES: usize = 256;
+
+// Per request. Keeps the multi-row upsert (5 bind parameters per token) under
+// SQLite's 32766 variable limit.
+const MAX_REGISTER_TOKENS: usize = 5000;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DepositTokenEntry {
+    Plain(String), // base64url(32 bytes)
+    Detailed {
+        token: String,                 // base64url(32 bytes)
+        label: Option<String>,         // base64url(<= MAX_LABEL_BYTES), client-encrypted
+        sender_pubkey: Option<String>, // base64url(32 bytes), Ed25519; deposits must be signed
+    },
+}
+
+#[derive(Serialize)]
+struct RegisterDepositTokensResp {
+    added: u64,
+    already_present: u64,
+    rejected: u64,
+    results: Vec<RegisteredToken>, // one per entry, in request order
+}
+
+#[derive(Serialize)]
+struct RegisteredToken {
+    deposit_token_hash: Option<String>, // None if the token itself didn't decode
+    status: &'static str,               // "added" | "present" | "rejected"
+    reason: Option<&'static str>,       // rejected only: "invalid" | "revoked"
+}
+
+impl RegisteredToken {
+    fn new(dep_hash: Option<&[u8]>, status: &'static str, reason: Option<&'static str>) -> Self {
+        RegisteredToken {
+            deposit_token_hash: dep_hash.map(b64url_encode),
+            status,
+            reason,
+        }
+    }
+}
+
+fn parse_deposit_token_entry(
+    server_secret: &[u8],
+    entry: DepositTokenEntry,
+) -> Result<NewDepositToken, Option<Vec<u8>>> {
+    let (t, label, sender_pubkey) = match entry {
+        DepositTokenEntry::Plain(t) => (t, None, None),
+        DepositTokenEntry::Detailed {
+            token,
+            label,
+            sender_pubkey,
+        } => (token, label, sender_pubkey),
+    };
+    let raw = match b64url_decode(&t) {
+        Ok(raw) if raw.len() == 32 => raw,
+        _ => return Err(None),
+    };
+    let dep_hash = hmac_hash(server_secret, &raw);
+    let label = match label {
+        Some(l) => match b64url_decode(&l) {
+            Ok(l) if l.len() <= MAX_LABEL_BYTES => Some(l),
+            _ => return Err(Some(dep_hash)),
+        },
+        None => None,
+    };
+    let sender_pubkey = match sender_pubkey {
+        Some(k) => match decode_ed25519_key(&k) {
+            Ok(k) => Some(k.to_bytes().to_vec()),
+            Err(_) => return Err(Some(dep_hash)),
+        },
+        None => None,
+    };
+    Ok(NewDepositToken {
+        dep_hash,
+        label,
+        sender_pubkey,
+    })
+}
+
+async fn register_deposit_tokens(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Payload(req): Payload<RegisterDepositTokensReq>,
+) -> Result<Json<RegisterDepositTokensResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    if req.deposit_tokens.is_empty() || req.deposit_tokens.len() > MAX_REGISTER_TOKENS {
+        return Err(ApiError::InvalidInput);
+    }
+
+    // Validate everything before writing; bad entries are rejected individually
+    let mut parsed = Vec::with_capacity(req.deposit_tokens.len());
+    let mut tokens = Vec::with_capacity(req.deposit_tokens.len());
+    for entry in req.deposit_tokens {
+        match parse_deposit_token_entry(&state.server_secret, entry) {
+            Ok(t) => {
+                parsed.push(Ok(t.dep_hash.clone()));
+                tokens.push(t);
+            }
+            Err(dep_hash) => parsed.push(Err(dep_hash)),
+        }
+    }
+
+    // Re-registering may change a token's sender key, so drop cached status
+    let hashes: Vec<Vec<u8>> = tokens.iter().map(|t| t.dep_hash.clone()).collect();
+    let outcomes = state
+        .shard(&mailbox_id)
+        .writer
+        .submit(|reply| WriteJob::RegisterTokens {
+            mailbox_id: mailbox_id.clone(),
+            tokens,
+            now: unix_ts(),
+            reply,
+        })
+        .await?;
+    for dep_hash in hashes {
+        state
+            .auth_cache
+            .invalidate(&AuthKey::Deposit(mailbox_id.clone(), dep_hash));
+    }
+
+    let mut outcomes = outcomes.into_iter();
+    let mut resp = RegisterDepositTokensResp {
+        added: 0,
+        already_present: 0,
+        rejected: 0,
+        results: Vec::with_capacity(parsed.len()),
+    };
+    for p in parsed {
+        let result = match p {
+            Ok(dep_hash) => match outcomes.next() {
+                Some(TokenOutcome::Added) => {
+                    resp.added += 1;
+                    RegisteredToken::new(Some(&dep_hash), "added", None)
+                }
+                Some(TokenOutcome::Present) => {
+                    resp.already_present += 1;
+                    RegisteredToken::new(Some(&dep_hash), "present", None)
+                }
+                Some(TokenOutcome::Revoked) => {
+                    resp.rejected += 1;
+                    RegisteredToken::new(Some(&dep_hash), "rejected", Some("revoked"))
+                }
+                None => return Err(ApiError::ServerError),
+            },
+            Err(dep_hash) => {
+                resp.rejected += 1;
+                RegisteredToken::new(dep_hash.as_deref(), "rejected", Some("invalid"))
+            }
+        };
+        resp.results.push(result);
+    }
+
+    Ok(Json(resp))
+}
+
+#[derive(Serialize)]
+struct DepositTokenInfo {
+    deposit_token_hash: String,
+    label: Option<String>,
+    sender_pubkey: Option<String>,
+    revoked: bool,
+    created_at: i64,
+}
+
+#[derive(Serialize)]
+struct ListDepositTokensResp {
+    deposit_tokens: Vec<DepositTokenInfo>,
+}
+
+async fn list_deposit_tokens(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<ListDepositTokensResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    let rows = sqlx::query(
+        "SELECT dep_hash, label, sender_pubkey, revoked, created_at FROM deposit_tokens WHERE mailbox_id = ? ORDER BY created_at ASC",
+    )
+    .bind(&mailbox_id)
+    .fetch_all(&state.shard(&mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    let mut deposit_tokens = Vec::with_capacity(rows.len());
+    for row in rows {
+        let dep_hash: Vec<u8> = row.try_get("dep_hash").map_err(|_| ApiError::ServerError)?;
+        let label: Option<Vec<u8>> = row.try_get("label").map_err(|_| ApiError::ServerError)?;
+        let sender_pubkey: Option<Vec<u8>> = row
+            .try_get("sender_pubkey")
+            .map_err(|_| ApiError::ServerError)?;
+        let revoked: i64 = row.try_get("revoked").map_err(|_| ApiError::ServerError)?;
+        let created_at: i64 = row
+            .try_get("created_at")
+            .map_err(|_| ApiError::ServerError)?;
+
+        deposit_tokens.push(DepositTokenInfo {
+            deposit_token_hash: b64url_encode(&dep_hash),
+            label: label.as_deref().map(b64url_encode),
+            sender_pubkey: sender_pubkey.as_deref().map(b64url_encode),
+            revoked: revoked != 0,
+            created_at,
+        });
+    }
+
+    Ok(Json(ListDepositTokensResp { deposit_tokens }))
+}
+
+fn header_msg_id(headers: &HeaderMap) -> Result<Vec<u8>, ApiError> {
+    let v = headers
+        .get("x-whisper-msgid")
+        .ok_or(ApiError::InvalidInput)?
+        .to_str()
+        .map_err(|_| ApiError::InvalidInput)?;
+    let raw = b64url_decode(v)?;
+    if !(16..=32).contains(&raw.len()) {
+        return Err(ApiError::InvalidInput);
+    }
+    Ok(raw)
+}
+
+// Expiry requested by a depositor or uploader
+enum Expiry {
+    At(i64),  // X-Whisper-ExpiresAt: unix seconds or RFC 3339
+    Ttl(i64), // X-Whisper-TTL: seconds from now
+}
+
+impl Expiry {
+    fn resolve(&self, now: i64) -> i64 {
+        match *self {
+            Expiry::At(t) => t,
+            Expiry::Ttl(secs) => now.saturating_add(secs),
+        }
+    }
+}
+
+// None if neither header is sent. Unparseable values and sending both
+// headers are InvalidInput rather than a silent fallback to the default.
+fn header_expiry(headers: &HeaderMap) -> Result<Option<Expiry>, ApiError> {
+    // Accept both spellings
+    let at = headers
+        .get("x-whisper-expiresat")
+        .or_else(|| headers.get("x-whisper-expires-at"));
+    let ttl = headers.get("x-whisper-ttl");
+
+    match (at, ttl) {
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => Err(ApiError::InvalidInput),
+        (Some(v), None) => {
+            let v = v.to_str().map_err(|_| ApiError::InvalidInput)?.trim();
+            let at = match v.parse::<i64>() {
+                Ok(ts) => ts,
+                Err(_) => OffsetDateTime::parse(v, &Rfc3339)
+                    .map_err(|_| ApiError::InvalidInput)?
+                    .unix_timestamp(),
+            };
+            Ok(Some(Expiry::At(at)))
+        }
+        (None, Some(v)) => match v
+            .to_str()
+            .map_err(|_| ApiError::InvalidInput)?
+            .trim()
+            .parse()
+        {
+            Ok(secs) if secs > 0 => Ok(Some(Expiry::Ttl(secs))),
+            _ => Err(ApiError::InvalidInput),
+        },
+    }
+}
+
+#[derive(Serialize)]
+struct DepositResp {
+    stored: bool,
+    msg_id: String,
+    expires_at: i64,
+}
+
+// Opaque sender hint size cap (decoded bytes). The server never interprets hints.
+const MAX_HINT_BYTES: usize = 256;
+
+// Room for multipart boundaries and part headers around the blob and hint,
+// when bounding the raw body before it is parsed. The parts themselves are
+// checked against their own limits afterwards.
+const MULTIPART_OVERHEAD_BYTES: usize = 4096;
+
+// The deposit body is either the raw blob, with an optional base64url
+// `X-Whisper-Hint` header, or multipart/form-data with a `blob` part and an
+// optional raw `hint` part.
+async fn deposit_body(
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<(Bytes, Option<Vec<u8>>), ApiError> {
+    let header_hint = match headers.get("x-whisper-hint") {
+        Some(v) => Some(b64url_decode(
+            v.to_str().map_err(|_| ApiError::InvalidInput)?,
+        )?),
+        None => None,
+    };
+    let multipart = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.trim_start()
+                .to_ascii_lowercase()
+                .starts_with("multipart/form-data")
+        });
+    if !multipart {
+        return Ok((body, header_hint));
+    }
+
+    let mut req = Request::new(Body::from(body));
+    if let Some(ct) = headers.get(header::CONTENT_TYPE) {
+        req.headers_mut().insert(header::CONTENT_TYPE, ct.clone());
+    }
+    let mut form = Multipart::from_request(req, &())
+        .await
+        .map_err(|_| ApiError::InvalidInput)?;
+    let (mut blob, mut hint) = (None, header_hint.map(Bytes::from));
+    while let Some(field) = form
+        .next_field()
+        .await
+        .map_err(|_| ApiError::InvalidInput)?
+    {
+        let slot = match field.name() {
+            Some("blob") if blob.is_none() => &mut blob,
+            Some("hint") if hint.is_none() => &mut hint,
+            _ => return Err(ApiError::InvalidInput),
+        };
+        let bytes = field.bytes().await.map_err(|_| ApiError::InvalidInput)?;
+        *slot = Some(bytes);
+    }
+    let blob = blob.ok_or(ApiError::InvalidInput)?;
+    Ok((blob, hint.map(|h| h.to_vec())))
+}
+
+async fn deposit(
+    State(state): State<Arc<AppState>>,
+    Path(address): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<DepositResp>, ApiError> {
+    if body.len() > state.largest_msg_bytes() + MAX_HINT_BYTES + MULTIPART_OVERHEAD_BYTES {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    let (body, hint) = deposit_body(&headers, body).await?;
+    if body.len() > state.largest_msg_bytes() {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    if hint.as_ref().is_some_and(|h| h.len() > MAX_HINT_BYTES) {
+        return Err(ApiError::InvalidInput);
+    }
+
+    // `address` is the mailbox id or one of its aliases
+    let depositor = auth_depositor(&state, &address, &headers, body.len()).await?;
+    let mailbox_id = depositor.mailbox_id.clone();
+
+    let (_, limits) = mailbox_limits(&state, &mailbox_id).await?;
+    if body.len() > limits.max_msg_bytes {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let msg_id_raw = header_msg_id(&headers)?;
+    let msg_id_b64 = b64url_encode(&msg_id_raw);
+    let body_hash = Sha256::digest(&body).to_vec();
+    let expiry = header_expiry(&headers)?;
+
+    // Sender-bound token: the deposit must be signed by the sender's key,
+    // over an absolute expiry
+    let sender_sig = match &depositor.sender_pubkey {
+        Some(pk) => {
+            let Some(Expiry::At(expires_at)) = expiry else {
+                return Err(ApiError::InvalidInput);
+            };
+            Some(verify_sender_signature(
+                &headers,
+                pk,
+                &address,
+                &msg_id_b64,
+                expires_at,
+                &body_hash,
+            )?)
+        }
+        None => None,
+    };
+
+    // Retry of an already committed deposit?
+    if let Some(resp) = replayed_deposit(&state, &mailbox_id, &msg_id_raw, &body_hash).await? {
+        return Ok(Json(resp));
     }
 
     // expires
     let now = unix_ts();
-    let mut expires_at = header_expires_at(&headers)
-        .unwrap_or_else(|| now + state.default_ttl_days * 24 * 3600);
-    let max_expires = now + state.max_ttl_days * 24 * 3600;
+    let mut expires_at = expiry.map_or(now + limits.ttl_secs, |e| e.resolve(now));
+    let max_expires = now + limits.max_ttl_secs;
+
+    if expires_at > max_expires {
+        // A signed expiry is stored verbatim so the owner can verify it
+        if sender_sig.is_some() {
+            return Err(ApiError::InvalidInput);
+        }
+        expires_at = max_expires;
+    }
+    if expires_at <= now {
+        tracing::warn!(expires_at, now, "deposit invalid expires_at");
+        return Err(ApiError::InvalidInput);
+    }
+
+    let res = state
+        .shard(&mailbox_id)
+        .writer
+        .submit(|reply| {
+            WriteJob::Deposit(
+                NewMessage {
+                    mailbox_id: mailbox_id.clone(),
+                    msg_id: msg_id_raw.clone(),
+                    blob: body,
+                    body_hash: body_hash.clone(),
+                    dep_hash: depositor.dep_hash,
+                    sender_sig,
+                    alias_id: depositor.alias_id,
+                    address: (address != mailbox_id).then_some(address),
+                    hint,
+                    received_at: now,
+                    expires_at,
+                    max_queue_messages: limits.max_queue_messages,
+                    max_queue_bytes: limits.max_queue_bytes,
+                },
+                reply,
+            )
+        })
+        .await;
+
+    match res {
+        Ok(()) => Ok(Json(DepositResp {
+            stored: true,
+            msg_id: msg_id_b64,
+            expires_at,
+        })),
+        Err(ApiError::Conflict) => {
+            // Lost a race against a concurrent attempt with the same msg_id
+            let replay = replayed_deposit(&state, &mailbox_id, &msg_id_raw, &body_hash).await?;
+            replay.map(Json).ok_or(ApiError::Conflict)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Verifies `X-Whisper-Sender-Signature`: Ed25519 by the token's sender key over
+//   "whisper-deposit-v1\n" address "\n" msg_id (base64url) "\n" expires_at "\n" hex(sha256(body))
+// where address is the one deposited to (mailbox id, alias or epoch address,
+// returned by poll as `deposit_address`) and expires_at is the absolute
+// `X-Whisper-ExpiresAt` value in unix seconds, required here.
+// Returns the raw signature.
+fn verify_sender_signature(
+    headers: &HeaderMap,
+    sender_pubkey: &[u8],
+    address: &str,
+    msg_id_b64: &str,
+    expires_at: i64,
+    body_hash: &[u8],
+) -> Result<Vec<u8>, ApiError> {
+    let signed_msg = format!(
+        "whisper-deposit-v1\n{}\n{}\n{}\n{}",
+        address,
+        msg_id_b64,
+        expires_at,
+        hex::encode(body_hash)
+    );
+    verify_sender(headers, sender_pubkey, &signed_msg)
+}
+
+// Verifies a prekey-bundle claim with a sender-bound token, so a stolen token
+// can't drain the one-time prekeys: `X-Whisper-Sender-Signature` by the
+// token's sender key over
+//   "whisper-prekey-v1\n" address "\n" timestamp "\n" nonce
+// with `X-Whisper-Sender-Timestamp` (unix seconds) and `X-Whisper-Sender-Nonce`
+// (base64url, 16-32 bytes) under the same skew window and replay protection
+// as signed owner requests.
+fn verify_sender_claim(
+    state: &AppState,
+    headers: &HeaderMap,
+    sender_pubkey: &[u8],
+    mailbox_id: &str,
+    address: &str,
+) -> Result<(), ApiError> {
+    let header = |name: &str| -> Result<&str, ApiError> {
+        headers
+            .get(name)
+            .ok_or(ApiError::Unauthorized)?
+            .to_str()
+            .map_err(|_| ApiError::Unauthorized)
+    };
+    let timestamp: i64 = header("x-whisper-sender-timestamp")?
+        .parse()
+        .map_err(|_| ApiError::Unauthorized)?;
+    let nonce = header("x-whisper-sender-nonce")?;
+    if !(16..=32).contains(&b64url_decode(nonce)?.len()) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let signed_msg = format!("whisper-prekey-v1\n{address}\n{timestamp}\n{nonce}");
+    verify_sender(headers, sender_pubkey, &signed_msg)?;
+    spend_nonce(
+        &state.owner_nonces,
+        format!("{mailbox_id}:sender:{nonce}"),
+        timestamp,
+        state.owner_sig_max_skew_secs,
+        unix_ts(),
+    )
+}
+
+// Checks `X-Whisper-Sender-Signature` over `signed_msg` against a sender key.
+// Returns the raw signature.
+fn verify_sender(
+    headers: &HeaderMap,
+    sender_pubkey: &[u8],
+    signed_msg: &str,
+) -> Result<Vec<u8>, ApiError> {
+    let key = <[u8; 32]>::try_from(sender_pubkey)
+        .ok()
+        .and_then(|k| VerifyingKey::from_bytes(&k).ok())
+        .ok_or(ApiError::ServerError)?;
+    let sig_raw = headers
+        .get("x-whisper-sender-signature")
+        .and_then(|h| h.to_str().ok())
+        .ok_or(ApiError::Unauthorized)
+        .and_then(b64url_decode)?;
+    let signature = Signature::from_slice(&sig_raw).map_err(|_| ApiError::Unauthorized)?;
+    key.verify_strict(signed_msg.as_bytes(), &signature)
+        .map_err(|_| ApiError::Forbidden)?;
+    Ok(sig_raw)
+}
+
+// Looks up an existing message with the same msg_id. Returns the original
+// response if the body matches, Conflict if it differs, None if absent.
+async fn replayed_deposit(
+    state: &AppState,
+    mailbox_id: &str,
+    msg_id_raw: &[u8],
+    body_hash: &[u8],
+) -> Result<Option<DepositResp>, ApiError> {
+    let row: Option<(Option<Vec<u8>>, i64)> = sqlx::query_as(
+        "SELECT body_hash, expires_at FROM messages WHERE mailbox_id = ? AND msg_id = ?",
+    )
+    .bind(mailbox_id)
+    .bind(msg_id_raw)
+    .fetch_optional(&state.shard(mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    match row {
+        None => Ok(None),
+        Some((Some(stored), expires_at)) if stored == body_hash => Ok(Some(DepositResp {
+            stored: true,
+            msg_id: b64url_encode(msg_id_raw),
+            expires_at,
+        })),
+        Some(_) => Err(ApiError::Conflict),
+    }
+}
+
+#[derive(Deserialize)]
+struct PollQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    fields: Option<String>, // "meta": no blobs, see PollMsgSummary
+    since: Option<i64>,     // only messages received at or after this unix ts
+}
+
+// `fields=meta` entry: enough to decide what to fetch via fetch_message
+#[derive(Serialize)]
+struct PollMsgSummary {
+    msg_id: String,
+    size: i64,
+    received_at: i64,
+    expires_at: i64,
+    hint: Option<String>, // base64url sender hint, if the deposit carried one
+}
+
+#[derive(Serialize)]
+struct PollMsgMeta {
+    msg_id: String,
+    received_at: i64,
+    expires_at: i64,
+    deposit_token_hash: Option<String>, // base64url dep_hash, as accepted by revoke
+    deposit_token_label: Option<String>,
+    sender_signature: Option<String>, // base64url, for sender-bound tokens
+    alias_id: Option<String>,         // alias the message was deposited through
+    deposit_address: String,          // address deposited to, as covered by sender_signature
+}
+
+#[derive(Serialize)]
+struct PollMsg {
+    #[serde(flatten)]
+    meta: PollMsgMeta,
+    blob_b64: String,
+}
+
+#[derive(Serialize)]
+struct CborPollMsg {
+    #[serde(flatten)]
+    meta: PollMsgMeta,
+    blob: CborBytes,
+}
+
+#[derive(Serialize)]
+struct PollResp<M> {
+    cursor: String,
+    messages: Vec<M>,
+    has_more: bool,
+    remaining: i64, // after this page; capped at POLL_REMAINING_CAP
+    server_time: i64,
+}
+
+impl<M> PollResp<M> {
+    fn map<N>(self, f: impl FnMut(M) -> N) -> PollResp<N> {
+        PollResp {
+            cursor: self.cursor,
+            messages: self.messages.into_iter().map(f).collect(),
+            has_more: self.has_more,
+            remaining: self.remaining,
+            server_time: self.server_time,
+        }
+    }
+}
+
+// Counting the rest of a large backlog isn't worth a full scan on every poll
+const POLL_REMAINING_CAP: i64 = 1000;
+
+// Serializes as a CBOR byte string rather than an array of integers
+struct CborBytes(Vec<u8>);
+
+impl Serialize for CborBytes {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bytes(&self.0)
+    }
+}
+
+// Poll response encodings, chosen from `Accept`:
+// - application/json (default): blobs as base64 in `blob_b64`
+// - application/cbor: the same fields, blobs as raw byte strings in `blob`
+// - application/vnd.whisper.frames: a sequence of frames, each a u32 BE
+//   length then that many bytes. The first frame is the JSON response
+//   without blobs; each message's raw blob follows, in order.
+#[derive(Clone, Copy, PartialEq)]
+enum PollFormat {
+    Json,
+    Cbor,
+    Frames,
+}
+
+const CBOR: &str = "application/cbor";
+const FRAMES: &str = "application/vnd.whisper.frames";
+
+fn poll_format(headers: &HeaderMap) -> PollFormat {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return PollFormat::Json;
+    };
+    // Highest q wins; ties go to the first listed
+    let mut best = (PollFormat::Json, 0.0);
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let format = match params.next().unwrap_or("").trim() {
+            t if t.eq_ignore_ascii_case(CBOR) => PollFormat::Cbor,
+            t if t.eq_ignore_ascii_case(FRAMES) => PollFormat::Frames,
+            t if t.eq_ignore_ascii_case("application/json") => PollFormat::Json,
+            _ => continue,
+        };
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if q > best.1 {
+            best = (format, q);
+        }
+    }
+    best.0
+}
+
+fn poll_response(
+    format: PollFormat,
+    page: PollResp<(PollMsgMeta, Vec<u8>)>,
+) -> Result<Response, ApiError> {
+    match format {
+        PollFormat::Json => {
+            let resp = page.map(|(meta, blob)| PollMsg {
+                meta,
+                blob_b64: base64::engine::general_purpose::STANDARD.encode(blob),
+            });
+            encode_poll(format, &resp, Vec::new())
+        }
+        PollFormat::Cbor => {
+            let resp = page.map(|(meta, blob)| CborPollMsg {
+                meta,
+                blob: CborBytes(blob),
+            });
+            encode_poll(format, &resp, Vec::new())
+        }
+        PollFormat::Frames => {
+            let mut blobs = Vec::with_capacity(page.messages.len());
+            let resp = page.map(|(meta, blob)| {
+                blobs.push(blob);
+                meta
+            });
+            encode_poll(format, &resp, blobs)
+        }
+    }
+}
+
+// `blobs` only apply to frames, where they follow the response frame
+fn encode_poll<T: Serialize>(
+    format: PollFormat,
+    resp: &T,
+    blobs: Vec<Vec<u8>>,
+) -> Result<Response, ApiError> {
+    let (content_type, body) = match format {
+        PollFormat::Json => (
+            "application/json",
+            serde_json::to_vec(resp).map_err(|_| ApiError::ServerError)?,
+        ),
+        PollFormat::Cbor => {
+            let mut body = Vec::new();
+            ciborium::into_writer(resp, &mut body).map_err(|_| ApiError::ServerError)?;
+            (CBOR, body)
+        }
+        PollFormat::Frames => {
+            let head = serde_json::to_vec(resp).map_err(|_| ApiError::ServerError)?;
+            let mut body = Vec::with_capacity(
+                4 + head.len() + blobs.iter().map(|b| 4 + b.len()).sum::<usize>(),
+            );
+            for frame in std::iter::once(&head).chain(&blobs) {
+                body.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                body.extend_from_slice(frame);
+            }
+            (FRAMES, body)
+        }
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::VARY, "accept"),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+async fn poll(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Query(q): Query<PollQuery>,
+) -> Result<Response, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Poll).await?;
+
+    let now = unix_ts();
+
+    let last_id = match q.cursor.as_deref() {
+        None => 0i64,
+        Some(c) => cursor_decode(&state.server_secret, &mailbox_id, c)?,
+    };
+
+    let limit = q
+        .limit
+        .unwrap_or(state.poll_limit_default)
+        .clamp(1, state.poll_limit_max);
+
+    let since = q.since.unwrap_or(0);
+
+    match q.fields.as_deref() {
+        None | Some("full") => {}
+        Some("meta") => {
+            return poll_meta(&state, &mailbox_id, &headers, last_id, limit, since, now).await
+        }
+        Some(_) => return Err(ApiError::InvalidInput),
+    }
+
+    // ✅ Runtime query (avoid sqlx::query! compile-time DB access)
+    let rows = sqlx::query(
+        r#"
+        SELECT m.id, m.msg_id, m.blob, m.dep_hash, d.label, m.sender_sig, m.alias_id,
+               COALESCE(m.address, m.alias_id, m.mailbox_id) AS deposit_address,
+               m.received_at, m.expires_at
+        FROM messages m
+        LEFT JOIN deposit_tokens d ON d.mailbox_id = m.mailbox_id AND d.dep_hash = m.dep_hash
+        WHERE m.mailbox_id = ? AND m.id > ? AND m.expires_at > ? AND m.received_at >= ?
+        ORDER BY m.id ASC
+        LIMIT ?
+        "#,
+    )
+    .bind(&mailbox_id)
+    .bind(last_id)
+    .bind(now)
+    .bind(since)
+    .bind(limit)
+    .fetch_all(&state.shard(&mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    let full_page = rows.len() as i64 == limit;
+    let mut msgs = Vec::with_capacity(rows.len());
+    let mut new_last_id = last_id;
+
+    for row in rows {
+        let id: i64 = row.try_get("id").map_err(|_| ApiError::ServerError)?;
+        let msg_id: Vec<u8> = row.try_get("msg_id").map_err(|_| ApiError::ServerError)?;
+        let blob: Vec<u8> = row.try_get("blob").map_err(|_| ApiError::ServerError)?;
+        let dep_hash: Option<Vec<u8>> =
+            row.try_get("dep_hash").map_err(|_| ApiError::ServerError)?;
+        let label: Option<Vec<u8>> = row.try_get("label").map_err(|_| ApiError::ServerError)?;
+        let sender_sig: Option<Vec<u8>> = row
+            .try_get("sender_sig")
+            .map_err(|_| ApiError::ServerError)?;
+        let alias_id: Option<String> =
+            row.try_get("alias_id").map_err(|_| ApiError::ServerError)?;
+        let deposit_address: String = row
+            .try_get("deposit_address")
+            .map_err(|_| ApiError::ServerError)?;
+        let received_at: i64 = row
+            .try_get("received_at")
+            .map_err(|_| ApiError::ServerError)?;
+        let expires_at: i64 = row
+            .try_get("expires_at")
+            .map_err(|_| ApiError::ServerError)?;
+
+        new_last_id = id;
+        let meta = PollMsgMeta {
+            msg_id: b64url_encode(&msg_id),
+            received_at,
+            expires_at,
+            deposit_token_hash: dep_hash.as_deref().map(b64url_encode),
+            deposit_token_label: label.as_deref().map(b64url_encode),
+            sender_signature: sender_sig.as_deref().map(b64url_encode),
+            alias_id,
+            deposit_address,
+        };
+        msgs.push((meta, blob));
+    }
+
+    let remaining = if full_page {
+        poll_remaining(&state, &mailbox_id, new_last_id, since, now).await?
+    } else {
+        0
+    };
+    let page = PollResp {
+        cursor: cursor_encode(&state.server_secret, &mailbox_id, new_last_id),
+        messages: msgs,
+        has_more: remaining > 0,
+        remaining,
+        server_time: now,
+    };
+    poll_response(poll_format(&headers), page)
+}
+
+// Messages left after `after_id` under the same filters as the page, counted
+// up to POLL_REMAINING_CAP.
+async fn poll_remaining(
+    state: &AppState,
+    mailbox_id: &str,
+    after_id: i64,
+    since: i64,
+    now: i64,
+) -> Result<i64, ApiError> {
+    let (remaining,): (i64,) = sqlx::query_as(
+        r#"
+        SELECT COUNT(*) FROM (
+            SELECT 1 FROM messages
+            WHERE mailbox_id = ? AND id > ? AND expires_at > ? AND received_at >= ?
+            LIMIT ?
+        )
+        "#,
+    )
+    .bind(mailbox_id)
+    .bind(after_id)
+    .bind(now)
+    .bind(since)
+    .bind(POLL_REMAINING_CAP)
+    .fetch_one(&state.shard(mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    Ok(remaining)
+}
+
+async fn poll_meta(
+    state: &AppState,
+    mailbox_id: &str,
+    headers: &HeaderMap,
+    last_id: i64,
+    limit: i64,
+    since: i64,
+    now: i64,
+) -> Result<Response, ApiError> {
+    let rows = sqlx::query(
+        r#"
+        SELECT m.id, m.msg_id, LENGTH(m.blob) AS size, m.received_at, m.expires_at, h.hint
+        FROM messages m
+        LEFT JOIN message_hints h ON h.mailbox_id = m.mailbox_id AND h.msg_id = m.msg_id
+        WHERE m.mailbox_id = ? AND m.id > ? AND m.expires_at > ? AND m.received_at >= ?
+        ORDER BY m.id ASC
+        LIMIT ?
+        "#,
+    )
+    .bind(mailbox_id)
+    .bind(last_id)
+    .bind(now)
+    .bind(since)
+    .bind(limit)
+    .fetch_all(&state.shard(mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    let full_page = rows.len() as i64 == limit;
+    let mut messages = Vec::with_capacity(rows.len());
+    let mut new_last_id = last_id;
+    for row in rows {
+        let id: i64 = row.try_get("id").map_err(|_| ApiError::ServerError)?;
+        let msg_id: Vec<u8> = row.try_get("msg_id").map_err(|_| ApiError::ServerError)?;
+        let hint: Option<Vec<u8>> = row.try_get("hint").map_err(|_| ApiError::ServerError)?;
+        new_last_id = id;
+        messages.push(PollMsgSummary {
+            msg_id: b64url_encode(&msg_id),
+            size: row.try_get("size").map_err(|_| ApiError::ServerError)?,
+            received_at: row
+                .try_get("received_at")
+                .map_err(|_| ApiError::ServerError)?,
+            expires_at: row
+                .try_get("expires_at")
+                .map_err(|_| ApiError::ServerError)?,
+            hint: hint.as_deref().map(b64url_encode),
+        });
+    }
+    let remaining = if full_page {
+        poll_remaining(state, mailbox_id, new_last_id, since, now).await?
+    } else {
+        0
+    };
+    let resp = PollResp {
+        cursor: cursor_encode(&state.server_secret, mailbox_id, new_last_id),
+        messages,
+        has_more: remaining > 0,
+        remaining,
+        server_time: now,
+    };
+    encode_poll(poll_format(headers), &resp, Vec::new())
+}
+
+// Parses a single-range `Range: bytes=...` against a blob of `size` bytes into
+// (start, len). Ok(None) means serve the whole blob (no or unsupported
+// header); Err(()) means the range is unsatisfiable.
+fn byte_range(headers: &HeaderMap, size: i64) -> Result<Option<(i64, i64)>, ()> {
+    let Some(spec) = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return Ok(None);
+    };
+    // Multiple ranges aren't supported; answering with the full body is allowed
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = match (first.parse::<i64>().ok(), last.parse::<i64>().ok()) {
+        (Some(start), Some(end)) if start <= end => (start, end.min(size - 1)),
+        (Some(start), None) if last.is_empty() => (start, size - 1),
+        (None, Some(suffix)) if first.is_empty() && suffix > 0 => {
+            ((size - suffix).max(0), size - 1)
+        }
+        _ => return Ok(None),
+    };
+    if start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end - start + 1)))
+}
+
+async fn fetch_message(
+    State(state): State<Arc<AppState>>,
+    Path((mailbox_id, msg_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Response, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Poll).await?;
+
+    let msg_id = b64url_decode(&msg_id)?;
+    let db = &state.shard(&mailbox_id).db;
+    let size: Option<(i64,)> = sqlx::query_as(
+        "SELECT LENGTH(blob) FROM messages WHERE mailbox_id = ? AND msg_id = ? AND expires_at > ?",
+    )
+    .bind(&mailbox_id)
+    .bind(&msg_id)
+    .bind(unix_ts())
+    .fetch_optional(db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    let (size,) = size.ok_or(ApiError::NotFound)?;
+
+    let range = match byte_range(&headers, size) {
+        Ok(range) => range,
+        Err(()) => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+            )
+                .into_response());
+        }
+    };
+    let (start, len) = range.unwrap_or((0, size));
+    // substr() on a BLOB is byte-based and 1-indexed
+    let blob: Option<(Vec<u8>,)> = sqlx::query_as(
+        "SELECT substr(blob, ?, ?) FROM messages WHERE mailbox_id = ? AND msg_id = ?",
+    )
+    .bind(start + 1)
+    .bind(len)
+    .bind(&mailbox_id)
+    .bind(&msg_id)
+    .fetch_optional(db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    let (blob,) = blob.ok_or(ApiError::NotFound)?;
+
+    let content_type = (header::CONTENT_TYPE, "application/octet-stream".to_string());
+    let accept_ranges = (header::ACCEPT_RANGES, "bytes".to_string());
+    if range.is_none() {
+        return Ok((StatusCode::OK, [content_type, accept_ranges], blob).into_response());
+    }
+    let content_range = (
+        header::CONTENT_RANGE,
+        format!("bytes {}-{}/{}", start, start + len - 1, size),
+    );
+    Ok((
+        StatusCode::PARTIAL_CONTENT,
+        [content_type, accept_ranges, content_range],
+        blob,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct AckReq {
+    msg_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AckResp {
+    deleted: u64,
+}
+
+async fn ack(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Payload(req): Payload<AckReq>,
+) -> Result<Json<AckResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Ack).await?;
+
+    if req.msg_ids.is_empty() || req.msg_ids.len() > 2000 {
+        return Err(ApiError::InvalidInput);
+    }
+
+    let mut raw_ids = Vec::with_capacity(req.msg_ids.len());
+    for msg_id in &req.msg_ids {
+        raw_ids.push(b64url_decode(msg_id)?);
+    }
+
+    let deleted = state
+        .shard(&mailbox_id)
+        .writer
+        .submit(|reply| WriteJob::Ack {
+            mailbox_id,
+            msg_ids: raw_ids,
+            reply,
+        })
+        .await?;
+
+    Ok(Json(AckResp { deleted }))
+}
+
+#[derive(Deserialize)]
+struct RevokeReq {
+    deposit_token_hashes: Vec<String>, // base64url(32 bytes)
+}
+
+#[derive(Serialize)]
+struct RevokeResp {
+    revoked: u64,
+}
+
+async fn revoke(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Payload(req): Payload<RevokeReq>,
+) -> Result<Json<RevokeResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    if req.deposit_token_hashes.is_empty() || req.deposit_token_hashes.len() > 1000 {
+        return Err(ApiError::InvalidInput);
+    }
+
+    let mut revoked_total: u64 = 0;
+    for h in req.deposit_token_hashes {
+        let raw = b64url_decode(&h)?;
+        if raw.len() != 32 {
+            return Err(ApiError::InvalidInput);
+        }
+        let res = sqlx::query(
+            "UPDATE deposit_tokens SET revoked = 1 WHERE mailbox_id = ? AND dep_hash = ?",
+        )
+        .bind(&mailbox_id)
+        .bind(&raw)
+        .execute(&state.shard(&mailbox_id).write_db)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+        state
+            .auth_cache
+            .invalidate(&AuthKey::Deposit(mailbox_id.clone(), raw));
+        revoked_total += res.rows_affected();
+    }
+
+    Ok(Json(RevokeResp {
+        revoked: revoked_total,
+    }))
+}
+
+#[derive(Serialize)]
+struct RevokePurgeResp {
+    revoked: u64,
+    purged: u64,
+}
+
+// Revokes deposit tokens and deletes every pending message they deposited,
+// atomically.
+async fn revoke_purge(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Payload(req): Payload<RevokeReq>,
+) -> Result<Json<RevokePurgeResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    if req.deposit_token_hashes.is_empty() || req.deposit_token_hashes.len() > 1000 {
+        return Err(ApiError::InvalidInput);
+    }
+
+    let mut hashes = Vec::with_capacity(req.deposit_token_hashes.len());
+    for h in &req.deposit_token_hashes {
+        let raw = b64url_decode(h)?;
+        if raw.len() != 32 {
+            return Err(ApiError::InvalidInput);
+        }
+        hashes.push(raw);
+    }
+
+    let mut tx = state
+        .shard(&mailbox_id)
+        .write_db
+        .begin()
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    let mut revoked_total: u64 = 0;
+    let mut purged_total: u64 = 0;
+    for raw in &hashes {
+        let res = sqlx::query(
+            "UPDATE deposit_tokens SET revoked = 1 WHERE mailbox_id = ? AND dep_hash = ?",
+        )
+        .bind(&mailbox_id)
+        .bind(raw)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+        revoked_total += res.rows_affected();
+
+        let (count, bytes): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(SUM(LENGTH(blob)), 0) FROM messages WHERE mailbox_id = ? AND dep_hash = ?",
+        )
+        .bind(&mailbox_id)
+        .bind(raw)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+        let res = sqlx::query("DELETE FROM messages WHERE mailbox_id = ? AND dep_hash = ?")
+            .bind(&mailbox_id)
+            .bind(raw)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+        release_queued(&mut tx, &mailbox_id, count, bytes)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+        purged_total += res.rows_affected();
+    }
+    tx.commit().await.map_err(|_| ApiError::ServerError)?;
+    for raw in hashes {
+        state
+            .auth_cache
+            .invalidate(&AuthKey::Deposit(mailbox_id.clone(), raw));
+    }
+
+    Ok(Json(RevokePurgeResp {
+        revoked: revoked_total,
+        purged: purged_total,
+    }))
+}
+
+#[derive(Deserialize, Serialize)]
+struct SignedPrekey {
+    key_id: u32,
+    public_key: String, // base64url(32 bytes), X25519
+    signature: String,  // base64url(64 bytes), Ed25519 over public_key
+}
+
+#[derive(Deserialize, Serialize)]
+struct OneTimePrekey {
+    key_id: u32,
+    public_key: String, // base64url(32 bytes), X25519
+}
+
+#[derive(Deserialize)]
+struct UploadPrekeysReq {
+    signed_prekey: Option<SignedPrekey>,
+    #[serde(default)]
+    one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+#[derive(Serialize)]
+struct PrekeyStatusResp {
+    signed_prekey_id: Option<u32>,
+    one_time_prekeys_remaining: i64,
+    low_watermark: i64,
+    replenish: bool,
+}
+
+fn decode_key(s: &str, len: usize) -> Result<Vec<u8>, ApiError> {
+    let raw = b64url_decode(s)?;
+    if raw.len() != len {
+        return Err(ApiError::InvalidInput);
+    }
+    Ok(raw)
+}
+
+async fn prekey_status_of(
+    state: &AppState,
+    mailbox_id: &str,
+) -> Result<PrekeyStatusResp, ApiError> {
+    let spk: Option<(i64,)> =
+        sqlx::query_as("SELECT key_id FROM signed_prekeys WHERE mailbox_id = ?")
+            .bind(mailbox_id)
+            .fetch_optional(&state.shard(mailbox_id).db)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+    let (remaining,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM one_time_prekeys WHERE mailbox_id = ?")
+            .bind(mailbox_id)
+            .fetch_one(&state.shard(mailbox_id).db)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+
+    Ok(PrekeyStatusResp {
+        signed_prekey_id: spk.map(|(id,)| id as u32),
+        one_time_prekeys_remaining: remaining,
+        low_watermark: state.prekey_low_watermark,
+        replenish: remaining < state.prekey_low_watermark,
+    })
+}
+
+async fn prekey_status(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<PrekeyStatusResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+    Ok(Json(prekey_status_of(&state, &mailbox_id).await?))
+}
+
+async fn upload_prekeys(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Payload(req): Payload<UploadPrekeysReq>,
+) -> Result<Json<PrekeyStatusResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    if req.signed_prekey.is_none() && req.one_time_prekeys.is_empty() {
+        return Err(ApiError::InvalidInput);
+    }
+    if req.one_time_prekeys.len() as i64 > state.max_one_time_prekeys {
+        return Err(ApiError::InvalidInput);
+    }
+
+    let spk = match &req.signed_prekey {
+        Some(spk) => Some((
+            spk.key_id,
+            decode_key(&spk.public_key, 32)?,
+            decode_key(&spk.signature, 64)?,
+        )),
+        None => None,
+    };
+    let mut otpks = Vec::with_capacity(req.one_time_prekeys.len());
+    for k in &req.one_time_prekeys {
+        otpks.push((k.key_id, decode_key(&k.public_key, 32)?));
+    }
+
+    let now = unix_ts();
+    let mut tx = state
+        .shard(&mailbox_id)
+        .write_db
+        .begin()
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+
+    if let Some((key_id, public_key, signature)) = spk {
+        sqlx::query(
+            r#"
+            INSERT INTO signed_prekeys (mailbox_id, key_id, public_key, signature, uploaded_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (mailbox_id) DO UPDATE SET
+              key_id = excluded.key_id,
+              public_key = excluded.public_key,
+              signature = excluded.signature,
+              uploaded_at = excluded.uploaded_at
+            "#,
+        )
+        .bind(&mailbox_id)
+        .bind(key_id as i64)
+        .bind(public_key)
+        .bind(signature)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    }
+
+    for (key_id, public_key) in otpks {
+        // Already-uploaded key ids are ignored so a retried upload is harmless
+        sqlx::query(
+            "INSERT OR IGNORE INTO one_time_prekeys (mailbox_id, key_id, public_key, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(&mailbox_id)
+        .bind(key_id as i64)
+        .bind(public_key)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    }
+
+    let (stored,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM one_time_prekeys WHERE mailbox_id = ?")
+            .bind(&mailbox_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+    if stored > state.max_one_time_prekeys {
+        return Err(ApiError::QuotaExceeded("one_time_prekeys"));
+    }
+
+    tx.commit().await.map_err(|_| ApiError::ServerError)?;
+
+    Ok(Json(prekey_status_of(&state, &mailbox_id).await?))
+}
+
+#[derive(Serialize)]
+struct PrekeyBundleResp {
+    signed_prekey: SignedPrekey,
+    one_time_prekey: Option<OneTimePrekey>,
+}
+
+// Hands out the signed prekey plus one one-time prekey, which is deleted in
+// the same statement so it is never given to two senders.
+async fn claim_prekey_bundle(
+    State(state): State<Arc<AppState>>,
+    Path(address): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<PrekeyBundleResp>, ApiError> {
+    let depositor = auth_depositor(&state, &address, &headers, 0).await?;
+    let mailbox_id = depositor.mailbox_id;
+    if let Some(pk) = &depositor.sender_pubkey {
+        verify_sender_claim(&state, &headers, pk, &mailbox_id, &address)?;
+    }
+
+    let spk: Option<(i64, Vec<u8>, Vec<u8>)> = sqlx::query_as(
+        "SELECT key_id, public_key, signature FROM signed_prekeys WHERE mailbox_id = ?",
+    )
+    .bind(&mailbox_id)
+    .fetch_optional(&state.shard(&mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    let Some((spk_id, spk_pub, spk_sig)) = spk else {
+        return Err(ApiError::NotFound);
+    };
+
+    let otpk: Option<(i64, Vec<u8>)> = sqlx::query_as(
+        r#"
+        DELETE FROM one_time_prekeys
+        WHERE id = (SELECT id FROM one_time_prekeys WHERE mailbox_id = ? ORDER BY id ASC LIMIT 1)
+        RETURNING key_id, public_key
+        "#,
+    )
+    .bind(&mailbox_id)
+    .fetch_optional(&state.shard(&mailbox_id).write_db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    Ok(Json(PrekeyBundleResp {
+        signed_prekey: SignedPrekey {
+            key_id: spk_id as u32,
+            public_key: b64url_encode(&spk_pub),
+            signature: b64url_encode(&spk_sig),
+        },
+        one_time_prekey: otpk.map(|(key_id, public_key)| OneTimePrekey {
+            key_id: key_id as u32,
+            public_key: b64url_encode(&public_key),
+        }),
+    }))
+}
+
+fn blob_hash_decode(s: &str) -> Result<Vec<u8>, ApiError> {
+    // lowercase hex SHA-256
+    if s.len() != 64 || s.bytes().any(|c| c.is_ascii_uppercase()) {
+        return Err(ApiError::InvalidInput);
+    }
+    hex::decode(s).map_err(|_| ApiError::InvalidInput)
+}
+
+#[derive(Serialize)]
+struct UploadBlobResp {
+    hash: String, // lowercase hex SHA-256
+    path: String,
+    size: usize,
+    expires_at: i64,
+}
+
+// Stores an immutable blob under its SHA-256. Uploading the same content
+// again only updates this mailbox's expiry for it.
+async fn upload_blob(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    body: Bytes,
+) -> Result<Json<UploadBlobResp>, ApiError> {
+    if body.len() > state.max_blob_bytes {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    if body.is_empty() {
+        return Err(ApiError::InvalidInput);
+    }
+
+    let now = unix_ts();
+    let mut expires_at = header_expiry(&headers)?
+        .map_or(now + state.default_blob_ttl_days * 24 * 3600, |e| {
+            e.resolve(now)
+        });
+    let max_expires = now + state.max_blob_ttl_days * 24 * 3600;
+    if expires_at > max_expires {
+        expires_at = max_expires;
+    }
+    if expires_at <= now {
+        return Err(ApiError::InvalidInput);
+    }
+
+    let hash = Sha256::digest(&body).to_vec();
+
+    let mut tx = state
+        .shard(&mailbox_id)
+        .write_db
+        .begin()
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    sqlx::query(
+        r#"
+        INSERT INTO blobs (mailbox_id, hash, content, created_at, expires_at)
+        VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT (mailbox_id, hash) DO UPDATE SET expires_at = excluded.expires_at
+        "#,
+    )
+    .bind(&mailbox_id)
+    .bind(&hash)
+    .bind(body.as_ref())
+    .bind(now)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    let (count,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM blobs WHERE mailbox_id = ? AND expires_at > ?")
+            .bind(&mailbox_id)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
+    if count > state.max_blobs_per_mailbox {
+        return Err(ApiError::QuotaExceeded("blobs"));
+    }
+    tx.commit().await.map_err(|_| ApiError::ServerError)?;
+
+    let hash_hex = hex::encode(&hash);
+    Ok(Json(UploadBlobResp {
+        path: format!("/v1/blobs/{hash_hex}"),
+        hash: hash_hex,
+        size: body.len(),
+        expires_at,
+    }))
+}
+
+#[derive(Serialize)]
+struct DeleteBlobResp {
+    deleted: u64,
+}
+
+async fn delete_blob(
+    State(state): State<Arc<AppState>>,
+    Path((mailbox_id, hash)): Path<(String, String)>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<DeleteBlobResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+    let hash = blob_hash_decode(&hash)?;
+
+    let res = sqlx::query("DELETE FROM blobs WHERE mailbox_id = ? AND hash = ?")
+        .bind(&mailbox_id)
+        .bind(hash)
+        .execute(&state.shard(&mailbox_id).write_db)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+
+    Ok(Json(DeleteBlobResp {
+        deleted: res.rows_affected(),
+    }))
+}
+
+// Public, unauthenticated read. Content is re-hashed before serving so a
+// corrupted row is never returned under the wrong address.
+async fn get_blob(
+    State(state): State<Arc<AppState>>,
+    Path(hash_hex): Path<String>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, ApiError> {
+    let hash = blob_hash_decode(&hash_hex)?;
+    let now = unix_ts();
+
+    // Blobs are addressed by hash alone, so any shard may hold a copy
+    let mut found: Option<(Vec<u8>, i64)> = None;
+    for shard in &state.shards {
+        let row: Option<(Vec<u8>, i64)> = sqlx::query_as(
+            "SELECT content, expires_at FROM blobs WHERE hash = ? AND expires_at > ? ORDER BY expires_at DESC LIMIT 1",
+        )
+        .bind(&hash)
+        .bind(now)
+        .fetch_optional(&shard.db)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+        if row.as_ref().map(|r| r.1) > found.as_ref().map(|f| f.1) {
+            found = row;
+        }
+    }
+    let Some((content, expires_at)) = found else {
+        return Err(ApiError::NotFound);
+    };
+
+    if Sha256::digest(&content).as_slice() != hash.as_slice() {
+        tracing::error!(hash = %hash_hex, "blob content does not match its hash");
+        return Err(ApiError::ServerError);
+    }
+
+    let etag = format!("\"{hash_hex}\"");
+    let cache_control = format!("public, max-age={}, immutable", expires_at - now);
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+    if not_modified {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), (header::CACHE_CONTROL, cache_control)],
+        )
+            .into_response());
+    }
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+        content,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct MintMacaroonReq {
+    #[serde(default)]
+    caveats: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MintMacaroonResp {
+    macaroon: String,
+}
+
+// Mints a macaroon for the mailbox, creating its root key nonce on first use.
+async fn mint_macaroon(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    OptionalPayload(req): OptionalPayload<MintMacaroonReq>,
+) -> Result<Json<MintMacaroonResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Admin).await?;
+
+    if req.caveats.len() > MAX_MACAROON_CAVEATS {
+        return Err(ApiError::InvalidInput);
+    }
+    for c in &req.caveats {
+        check_caveat(c, Op::Poll, 0, 0)?;
+    }
+
+    let mut nonce = vec![0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    sqlx::query(
+        "UPDATE mailboxes SET macaroon_nonce = COALESCE(macaroon_nonce, ?) WHERE mailbox_id = ?",
+    )
+    .bind(&nonce)
+    .bind(&mailbox_id)
+    .execute(&state.shard(&mailbox_id).write_db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    let (nonce,): (Vec<u8>,) =
+        sqlx::query_as("SELECT macaroon_nonce FROM mailboxes WHERE mailbox_id = ?")
+            .bind(&mailbox_id)
+            .fetch_one(&state.shard(&mailbox_id).db)
+            .await
+            .map_err(|_| ApiError::ServerError)?;
 
-    if expires_at > max_expires {
-        expires_at = max_expires;
-    }
-    if expires_at <= now {
-        tracing::warn!(expires_at, now, "deposit invalid expires_at");
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    let root_key = macaroon_root_key(&state.server_secret, &mailbox_id, &nonce);
+    let sig = macaroon_chain(&root_key, &id, &req.caveats)
+        .finalize()
+        .into_bytes();
+
+    let m = Macaroon {
+        id: b64url_encode(&id),
+        caveats: req.caveats,
+        sig: b64url_encode(&sig),
+    };
+    let json = serde_json::to_vec(&m).map_err(|_| ApiError::ServerError)?;
+    Ok(Json(MintMacaroonResp {
+        macaroon: b64url_encode(&json),
+    }))
+}
+
+#[derive(Serialize)]
+struct RevokeMacaroonsResp {
+    revoked: bool,
+}
+
+// Drops the root key nonce, invalidating every macaroon minted so far.
+async fn revoke_macaroons(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<RevokeMacaroonsResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Admin).await?;
+
+    let res = sqlx::query(
+        "UPDATE mailboxes SET macaroon_nonce = NULL WHERE mailbox_id = ? AND macaroon_nonce IS NOT NULL",
+    )
+    .bind(&mailbox_id)
+    .execute(&state.shard(&mailbox_id).write_db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    Ok(Json(RevokeMacaroonsResp {
+        revoked: res.rows_affected() > 0,
+    }))
+}
+
+#[derive(Deserialize)]
+struct CreateCredentialReq {
+    scopes: Vec<String>, // subset of "poll", "ack", "manage"
+}
+
+#[derive(Serialize)]
+struct CreateCredentialResp {
+    credential: String,      // base64url(32 bytes), used as a bearer token
+    credential_hash: String, // base64url, identifies it for listing / revoking
+    scopes: Vec<String>,
+}
+
+// Creates an owner credential limited to `scopes` (full-power owner only).
+async fn create_credential(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Payload(req): Payload<CreateCredentialReq>,
+) -> Result<Json<CreateCredentialResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Admin).await?;
+
+    if req.scopes.is_empty() {
         return Err(ApiError::InvalidInput);
     }
+    let mut scopes: Vec<String> = Vec::with_capacity(req.scopes.len());
+    for sc in req.scopes {
+        match Op::parse(&sc) {
+            Some(Op::Poll | Op::Ack | Op::Manage) => {}
+            _ => return Err(ApiError::InvalidInput),
+        }
+        if !scopes.contains(&sc) {
+            scopes.push(sc);
+        }
+    }
+
+    let mut raw = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut raw);
+    let cred_hash = hmac_hash(&state.server_secret, &raw);
 
-    // queue bytes quota
-    let (queue_bytes,): (i64,) = sqlx::query_as(
-        "SELECT COALESCE(SUM(LENGTH(blob)),0) as bytes FROM messages WHERE mailbox_id = ?",
+    sqlx::query(
+        "INSERT INTO owner_credentials (mailbox_id, cred_hash, scopes, revoked, created_at) VALUES (?, ?, ?, 0, ?)",
     )
     .bind(&mailbox_id)
-    .fetch_one(&state.db)
+    .bind(&cred_hash)
+    .bind(scopes.join(","))
+    .bind(unix_ts())
+    .execute(&state.shard(&mailbox_id).write_db)
     .await
     .map_err(|_| ApiError::ServerError)?;
-    if queue_bytes + body.len() as i64 > state.max_queue_bytes {
-        return Err(ApiError::RateLimited);
+
+    Ok(Json(CreateCredentialResp {
+        credential: b64url_encode(&raw),
+        credential_hash: b64url_encode(&cred_hash),
+        scopes,
+    }))
+}
+
+#[derive(Serialize)]
+struct CredentialInfo {
+    credential_hash: String,
+    scopes: Vec<String>,
+    revoked: bool,
+    created_at: i64,
+}
+
+#[derive(Serialize)]
+struct ListCredentialsResp {
+    credentials: Vec<CredentialInfo>,
+}
+
+async fn list_credentials(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<ListCredentialsResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Admin).await?;
+
+    let rows = sqlx::query(
+        "SELECT cred_hash, scopes, revoked, created_at FROM owner_credentials WHERE mailbox_id = ? ORDER BY created_at ASC",
+    )
+    .bind(&mailbox_id)
+    .fetch_all(&state.shard(&mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    let mut credentials = Vec::with_capacity(rows.len());
+    for row in rows {
+        let cred_hash: Vec<u8> = row
+            .try_get("cred_hash")
+            .map_err(|_| ApiError::ServerError)?;
+        let scopes: String = row.try_get("scopes").map_err(|_| ApiError::ServerError)?;
+        let revoked: i64 = row.try_get("revoked").map_err(|_| ApiError::ServerError)?;
+        let created_at: i64 = row
+            .try_get("created_at")
+            .map_err(|_| ApiError::ServerError)?;
+
+        credentials.push(CredentialInfo {
+            credential_hash: b64url_encode(&cred_hash),
+            scopes: scopes.split(',').map(str::to_string).collect(),
+            revoked: revoked != 0,
+            created_at,
+        });
     }
 
-    let msg_id_raw = header_msg_id(&headers)?;
-    let msg_id_b64 = b64url_encode(&msg_id_raw);
+    Ok(Json(ListCredentialsResp { credentials }))
+}
 
-    // Insert with idempotence
+async fn revoke_credential(
+    State(state): State<Arc<AppState>>,
+    Path((mailbox_id, cred_hash)): Path<(String, String)>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<RevokeResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Admin).await?;
+
+    let raw = b64url_decode(&cred_hash)?;
+    if raw.len() != 32 {
+        return Err(ApiError::InvalidInput);
+    }
     let res = sqlx::query(
-        "INSERT INTO messages (mailbox_id, msg_id, blob, received_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+        "UPDATE owner_credentials SET revoked = 1 WHERE mailbox_id = ? AND cred_hash = ? AND revoked = 0",
     )
     .bind(&mailbox_id)
-    .bind(&msg_id_raw)
-    .bind(body.as_ref())
+    .bind(&raw)
+    .execute(&state.shard(&mailbox_id).write_db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    state
+        .auth_cache
+        .invalidate(&AuthKey::Owner(mailbox_id.clone(), raw));
+
+    Ok(Json(RevokeResp {
+        revoked: res.rows_affected(),
+    }))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CreateAliasReq {
+    #[serde(default)]
+    deposit_token_hashes: Vec<String>, // base64url dep_hash values; empty = any token
+}
+
+#[derive(Serialize)]
+struct AliasInfo {
+    alias_id: String,
+    deposit_token_hashes: Vec<String>,
+    revoked: bool,
+    created_at: i64,
+}
+
+// Mints an alias id that routes deposits to this mailbox, optionally
+// restricted to the given deposit tokens.
+async fn create_alias(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    OptionalPayload(req): OptionalPayload<CreateAliasReq>,
+) -> Result<Json<AliasInfo>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    if req.deposit_token_hashes.len() > 1000 {
+        return Err(ApiError::InvalidInput);
+    }
+    let mut hashes = Vec::with_capacity(req.deposit_token_hashes.len());
+    for h in &req.deposit_token_hashes {
+        let raw = b64url_decode(h)?;
+        if raw.len() != 32 {
+            return Err(ApiError::InvalidInput);
+        }
+        hashes.push(raw);
+    }
+
+    let now = unix_ts();
+    let alias_id = random_b64url(24);
+
+    let mut tx = state
+        .shard(&mailbox_id)
+        .write_db
+        .begin()
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    sqlx::query(
+        "INSERT INTO mailbox_aliases (alias_id, mailbox_id, revoked, created_at) VALUES (?, ?, 0, ?)",
+    )
+    .bind(&alias_id)
+    .bind(&mailbox_id)
     .bind(now)
-    .bind(expires_at)
-    .execute(&state.db)
-    .await;
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    for h in &hashes {
+        sqlx::query(
+            "INSERT OR IGNORE INTO alias_deposit_tokens (alias_id, dep_hash) VALUES (?, ?)",
+        )
+        .bind(&alias_id)
+        .bind(h)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    }
+    tx.commit().await.map_err(|_| ApiError::ServerError)?;
 
-    match res {
-        Ok(_) => Ok(Json(DepositResp {
-            stored: true,
-            msg_id: msg_id_b64,
-            expires_at,
-        })),
-        Err(e) => {
-            if format!("{e}").to_lowercase().contains("unique") {
-                return Err(ApiError::Conflict);
-            }
-            Err(ApiError::ServerError)
+    Ok(Json(AliasInfo {
+        alias_id,
+        deposit_token_hashes: req.deposit_token_hashes,
+        revoked: false,
+        created_at: now,
+    }))
+}
+
+#[derive(Serialize)]
+struct ListAliasesResp {
+    aliases: Vec<AliasInfo>,
+}
+
+async fn list_aliases(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<ListAliasesResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT a.alias_id, a.revoked, a.created_at, t.dep_hash
+        FROM mailbox_aliases a
+        LEFT JOIN alias_deposit_tokens t ON t.alias_id = a.alias_id
+        WHERE a.mailbox_id = ?
+        ORDER BY a.created_at ASC, a.alias_id ASC
+        "#,
+    )
+    .bind(&mailbox_id)
+    .fetch_all(&state.shard(&mailbox_id).db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+
+    let mut aliases: Vec<AliasInfo> = Vec::new();
+    for row in rows {
+        let alias_id: String = row.try_get("alias_id").map_err(|_| ApiError::ServerError)?;
+        let revoked: i64 = row.try_get("revoked").map_err(|_| ApiError::ServerError)?;
+        let created_at: i64 = row
+            .try_get("created_at")
+            .map_err(|_| ApiError::ServerError)?;
+        let dep_hash: Option<Vec<u8>> =
+            row.try_get("dep_hash").map_err(|_| ApiError::ServerError)?;
+
+        if aliases.last().map(|a| &a.alias_id) != Some(&alias_id) {
+            aliases.push(AliasInfo {
+                alias_id,
+                deposit_token_hashes: Vec::new(),
+                revoked: revoked != 0,
+                created_at,
+            });
+        }
+        if let (Some(h), Some(a)) = (dep_hash, aliases.last_mut()) {
+            a.deposit_token_hashes.push(b64url_encode(&h));
+        }
+    }
+
+    Ok(Json(ListAliasesResp { aliases }))
+}
+
+async fn revoke_alias(
+    State(state): State<Arc<AppState>>,
+    Path((mailbox_id, alias_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<RevokeResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    let res = sqlx::query(
+        "UPDATE mailbox_aliases SET revoked = 1 WHERE mailbox_id = ? AND alias_id = ? AND revoked = 0",
+    )
+    .bind(&mailbox_id)
+    .bind(&alias_id)
+    .execute(&state.shard(&mailbox_id).write_db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    state
+        .auth_cache
+        .invalidate(&AuthKey::Address(alias_id.clone()));
+    state.auth_cache.invalidate(&AuthKey::AliasTokens(alias_id));
+
+    Ok(Json(RevokeResp {
+        revoked: res.rows_affected(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct EnableRotationReq {
+    rotation_secret: String, // base64url(32 bytes), shared with contacts
+    epoch_secs: Option<i64>, // default: one day
+}
+
+#[derive(Serialize)]
+struct RotationResp {
+    epoch_secs: i64,
+    current_epoch: i64,
+    current_address: String,
+}
+
+// Turns on epoch-rotating addresses (or replaces the secret / epoch length).
+async fn enable_rotation(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Payload(req): Payload<EnableRotationReq>,
+) -> Result<Json<RotationResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    let secret = b64url_decode(&req.rotation_secret)?;
+    if secret.len() != 32 {
+        return Err(ApiError::InvalidInput);
+    }
+    let epoch_secs = req.epoch_secs.unwrap_or(24 * 3600);
+    if !(60..=30 * 24 * 3600).contains(&epoch_secs) {
+        return Err(ApiError::InvalidInput);
+    }
+
+    let mut tx = state
+        .shard(&mailbox_id)
+        .write_db
+        .begin()
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    sqlx::query(
+        "UPDATE mailboxes SET rotation_secret = ?, rotation_epoch_secs = ? WHERE mailbox_id = ?",
+    )
+    .bind(&secret)
+    .bind(epoch_secs)
+    .bind(&mailbox_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    // Addresses derived from a previous secret stop resolving right away
+    sqlx::query("DELETE FROM epoch_addresses WHERE mailbox_id = ?")
+        .bind(&mailbox_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    tx.commit().await.map_err(|_| ApiError::ServerError)?;
+    state.auth_cache.invalidate_mailbox(&mailbox_id);
+
+    let now = unix_ts();
+    refresh_epoch_addresses(&state.shard(&mailbox_id).write_db, Some(&mailbox_id), now)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+
+    let current_epoch = now / epoch_secs;
+    Ok(Json(RotationResp {
+        epoch_secs,
+        current_epoch,
+        current_address: epoch_address(&secret, current_epoch),
+    }))
+}
+
+#[derive(Serialize)]
+struct DisableRotationResp {
+    disabled: bool,
+}
+
+async fn disable_rotation(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<DisableRotationResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    let mut tx = state
+        .shard(&mailbox_id)
+        .write_db
+        .begin()
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    let res = sqlx::query(
+        "UPDATE mailboxes SET rotation_secret = NULL, rotation_epoch_secs = NULL WHERE mailbox_id = ? AND rotation_secret IS NOT NULL",
+    )
+    .bind(&mailbox_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    sqlx::query("DELETE FROM epoch_addresses WHERE mailbox_id = ?")
+        .bind(&mailbox_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    tx.commit().await.map_err(|_| ApiError::ServerError)?;
+    state.auth_cache.invalidate_mailbox(&mailbox_id);
+
+    Ok(Json(DisableRotationResp {
+        disabled: res.rows_affected() > 0,
+    }))
+}
+
+// Proof-of-work challenge = base64url( expires_at (8 bytes BE) || random (16) || mac (16) )
+// mac = HMAC(server_secret, "pow" || expires_at || random)[..16]
+// Solved by a nonce with SHA-256(challenge || nonce) having at least
+// `difficulty_bits` leading zero bits.
+const POW_CHALLENGE_LEN: usize = 40;
+const MAX_POW_NONCE_BYTES: usize = 64;
+const MAX_POW_SPENT: usize = 100_000;
+
+fn pow_mac(server_secret: &[u8], head: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(server_secret).expect("HMAC key");
+    mac.update(b"pow");
+    mac.update(head);
+    mac.finalize().into_bytes()[..16].to_vec()
+}
+
+fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+    for b in digest {
+        if *b != 0 {
+            return bits + b.leading_zeros();
         }
+        bits += 8;
+    }
+    bits
+}
+
+#[derive(Deserialize)]
+struct PowSolution {
+    challenge: String, // as issued by GET /v1/pow-challenge
+    nonce: String,     // base64url(<= MAX_POW_NONCE_BYTES)
+}
+
+// Returns the challenge, now marked spent.
+fn check_pow(state: &AppState, pow: &PowSolution, now: i64) -> Result<Vec<u8>, ApiError> {
+    let challenge = b64url_decode(&pow.challenge)?;
+    let nonce = b64url_decode(&pow.nonce)?;
+    if challenge.len() != POW_CHALLENGE_LEN || nonce.len() > MAX_POW_NONCE_BYTES {
+        return Err(ApiError::InvalidInput);
+    }
+    let (head, mac) = challenge.split_at(24);
+    if !ct_eq(mac, &pow_mac(&state.server_secret, head)) {
+        return Err(ApiError::Forbidden);
+    }
+    let expires_at = i64::from_be_bytes(head[..8].try_into().unwrap());
+    if expires_at <= now {
+        return Err(ApiError::Forbidden);
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(&challenge);
+    hasher.update(&nonce);
+    if leading_zero_bits(&hasher.finalize()) < state.pow_difficulty_bits {
+        return Err(ApiError::Forbidden);
+    }
+
+    // Each challenge admits one mailbox
+    let mut spent = state.pow_spent.lock().unwrap();
+    if spent.len() >= MAX_POW_SPENT {
+        spent.retain(|_, exp| *exp > now);
+    }
+    if spent.len() >= MAX_POW_SPENT {
+        return Err(ApiError::RateLimited);
+    }
+    if spent.insert(challenge.clone(), expires_at).is_some() {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(challenge)
+}
+
+#[derive(Serialize)]
+struct PowChallengeResp {
+    challenge: String,
+    difficulty_bits: u32,
+    expires_at: i64,
+}
+
+async fn pow_challenge(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PowChallengeResp>, ApiError> {
+    if state.pow_difficulty_bits == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    let expires_at = unix_ts() + state.pow_challenge_ttl_secs;
+    let mut head = [0u8; 24];
+    head[..8].copy_from_slice(&expires_at.to_be_bytes());
+    rand::thread_rng().fill_bytes(&mut head[8..]);
+    let mut challenge = head.to_vec();
+    challenge.extend_from_slice(&pow_mac(&state.server_secret, &head));
+
+    Ok(Json(PowChallengeResp {
+        challenge: b64url_encode(&challenge),
+        difficulty_bits: state.pow_difficulty_bits,
+        expires_at,
+    }))
+}
+
+// Operator endpoints, authorized by `Authorization: Bearer <ADMIN_TOKEN>`.
+// They don't exist unless ADMIN_TOKEN is configured.
+fn auth_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = &state.admin_token else {
+        return Err(ApiError::NotFound);
+    };
+    let token = bearer_token(headers)?;
+    if !ct_eq(token.as_bytes(), expected.as_bytes()) {
+        return Err(ApiError::Forbidden);
     }
+    Ok(())
+}
+
+const MAX_TIER_BYTES: usize = 64;
+
+fn valid_tier(tier: &str) -> bool {
+    !tier.is_empty()
+        && tier.len() <= MAX_TIER_BYTES
+        && tier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
 }
 
 #[derive(Deserialize)]
-struct PollQuery {
-    cursor: Option<String>,
-    limit: Option<i64>,
+struct CreateInvitesReq {
+    count: Option<usize>,  // codes to issue, default 1
+    uses: Option<i64>,     // mailboxes each code may create, default 1
+    tier: Option<String>,  // assigned to mailboxes created with the code
+    ttl_secs: Option<i64>, // codes never expire if absent
 }
 
 #[derive(Serialize)]
-struct PollMsg {
-    msg_id: String,
-    received_at: i64,
-    expires_at: i64,
-    blob_b64: String,
+struct IssuedInvite {
+    invite_code: String, // base64url(32 bytes), handed to the user
+    invite_hash: String, // base64url, identifies it for listing / revoking
 }
 
 #[derive(Serialize)]
-struct PollResp {
-    cursor: String,
-    messages: Vec<PollMsg>,
+struct CreateInvitesResp {
+    invites: Vec<IssuedInvite>,
+    uses: i64,
+    tier: Option<String>,
+    expires_at: Option<i64>,
 }
 
-async fn poll(
+async fn create_invites(
     State(state): State<Arc<AppState>>,
-    Path(mailbox_id): Path<String>,
     headers: HeaderMap,
-    Query(q): Query<PollQuery>,
-) -> Result<Json<PollResp>, ApiError> {
-    // Auth poll token
-    let token = bearer_token(&headers)?;
-    let token_raw = b64url_decode(&token)?;
-    if token_raw.len() != 32 {
-        return Err(ApiError::Unauthorized);
-    }
-    let poll_hash = hmac_hash(&state.server_secret, &token_raw);
+    Payload(req): Payload<CreateInvitesReq>,
+) -> Result<Json<CreateInvitesResp>, ApiError> {
+    auth_admin(&state, &headers)?;
 
-    let mb: Option<(Vec<u8>,)> =
-        sqlx::query_as("SELECT poll_hash FROM mailboxes WHERE mailbox_id = ?")
-            .bind(&mailbox_id)
-            .fetch_optional(&state.db)
-            .await
-            .map_err(|_| ApiError::ServerError)?;
-    let Some((stored_hash,)) = mb else {
-        return Err(ApiError::NotFound);
+    let count = req.count.unwrap_or(1);
+    let uses = req.uses.unwrap_or(1);
+    if !(1..=1000).contains(&count) || !(1..=1_000_000).contains(&uses) {
+        return Err(ApiError::InvalidInput);
+    }
+    if req
+        .tier
+        .as_deref()
+        .is_some_and(|t| !valid_tier(t) || !state.tiers.contains_key(t))
+    {
+        return Err(ApiError::InvalidInput);
+    }
+    let now = unix_ts();
+    let expires_at = match req.ttl_secs {
+        Some(ttl) if ttl > 0 => Some(now + ttl),
+        Some(_) => return Err(ApiError::InvalidInput),
+        None => None,
     };
 
-    if stored_hash != poll_hash {
-        return Err(ApiError::Forbidden);
+    let mut tx = state
+        .directory()
+        .write_db
+        .begin()
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+    let mut invites = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut raw = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut raw);
+        let code_hash = hmac_hash(&state.server_secret, &raw);
+        sqlx::query(
+            "INSERT INTO invites (code_hash, uses_left, tier, expires_at, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&code_hash)
+        .bind(uses)
+        .bind(&req.tier)
+        .bind(expires_at)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+        invites.push(IssuedInvite {
+            invite_code: b64url_encode(&raw),
+            invite_hash: b64url_encode(&code_hash),
+        });
     }
+    tx.commit().await.map_err(|_| ApiError::ServerError)?;
 
-    let now = unix_ts();
+    Ok(Json(CreateInvitesResp {
+        invites,
+        uses,
+        tier: req.tier,
+        expires_at,
+    }))
+}
 
-    let last_id = match q.cursor.as_deref() {
-        None => 0i64,
-        Some(c) => cursor_decode(&state.server_secret, &mailbox_id, c)?,
-    };
+#[derive(Serialize)]
+struct InviteInfo {
+    invite_hash: String,
+    uses_left: i64,
+    tier: Option<String>,
+    expires_at: Option<i64>,
+    created_at: i64,
+}
 
-    let limit = q
-        .limit
-        .unwrap_or(state.poll_limit_default)
-        .clamp(1, state.poll_limit_max);
+#[derive(Serialize)]
+struct ListInvitesResp {
+    invites: Vec<InviteInfo>,
+}
+
+async fn list_invites(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<ListInvitesResp>, ApiError> {
+    auth_admin(&state, &headers)?;
 
-    // ✅ Runtime query (avoid sqlx::query! compile-time DB access)
     let rows = sqlx::query(
-        r#"
-        SELECT id, msg_id, blob, received_at, expires_at
-        FROM messages
-        WHERE mailbox_id = ? AND id > ? AND expires_at > ?
-        ORDER BY id ASC
-        LIMIT ?
-        "#,
+        "SELECT code_hash, uses_left, tier, expires_at, created_at FROM invites ORDER BY created_at ASC",
     )
-    .bind(&mailbox_id)
-    .bind(last_id)
-    .bind(now)
-    .bind(limit)
-    .fetch_all(&state.db)
+    .fetch_all(&state.directory().db)
     .await
     .map_err(|_| ApiError::ServerError)?;
 
-    let mut msgs = Vec::with_capacity(rows.len());
-    let mut new_last_id = last_id;
-
+    let mut invites = Vec::with_capacity(rows.len());
     for row in rows {
-        let id: i64 = row.try_get("id").map_err(|_| ApiError::ServerError)?;
-        let msg_id: Vec<u8> = row.try_get("msg_id").map_err(|_| ApiError::ServerError)?;
-        let blob: Vec<u8> = row.try_get("blob").map_err(|_| ApiError::ServerError)?;
-        let received_at: i64 = row.try_get("received_at").map_err(|_| ApiError::ServerError)?;
-        let expires_at: i64 = row.try_get("expires_at").map_err(|_| ApiError::ServerError)?;
-
-        new_last_id = id;
-        msgs.push(PollMsg {
-            msg_id: b64url_encode(&msg_id),
-            received_at,
-            expires_at,
-            blob_b64: base64::engine::general_purpose::STANDARD.encode(blob),
+        let code_hash: Vec<u8> = row
+            .try_get("code_hash")
+            .map_err(|_| ApiError::ServerError)?;
+        invites.push(InviteInfo {
+            invite_hash: b64url_encode(&code_hash),
+            uses_left: row
+                .try_get("uses_left")
+                .map_err(|_| ApiError::ServerError)?,
+            tier: row.try_get("tier").map_err(|_| ApiError::ServerError)?,
+            expires_at: row
+                .try_get("expires_at")
+                .map_err(|_| ApiError::ServerError)?,
+            created_at: row
+                .try_get("created_at")
+                .map_err(|_| ApiError::ServerError)?,
         });
     }
 
-    Ok(Json(PollResp {
-        cursor: cursor_encode(&state.server_secret, &mailbox_id, new_last_id),
-        messages: msgs,
-    }))
+    Ok(Json(ListInvitesResp { invites }))
 }
 
-#[derive(Deserialize)]
-struct AckReq {
-    msg_ids: Vec<String>,
+#[derive(Serialize)]
+struct RevokeInviteResp {
+    revoked: bool,
+}
+
+async fn revoke_invite(
+    State(state): State<Arc<AppState>>,
+    Path(invite_hash): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<RevokeInviteResp>, ApiError> {
+    auth_admin(&state, &headers)?;
+
+    let code_hash = b64url_decode(&invite_hash)?;
+    let res = sqlx::query("DELETE FROM invites WHERE code_hash = ?")
+        .bind(code_hash)
+        .execute(&state.directory().write_db)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+
+    Ok(Json(RevokeInviteResp {
+        revoked: res.rows_affected() > 0,
+    }))
 }
 
 #[derive(Serialize)]
-struct AckResp {
-    deleted: u64,
+struct MailboxStatusResp {
+    tier: Option<String>,
+    limits: Limits,      // effective
+    tier_limits: Limits, // allowance; the owner may lower limits below it
+    queued_messages: i64,
+    queued_bytes: i64,
+    created_at: i64,
 }
 
-async fn ack(
+async fn mailbox_status(
     State(state): State<Arc<AppState>>,
     Path(mailbox_id): Path<String>,
     headers: HeaderMap,
-    Json(req): Json<AckReq>,
-) -> Result<Json<AckResp>, ApiError> {
-    // Auth poll token
-    let token = bearer_token(&headers)?;
-    let token_raw = b64url_decode(&token)?;
-    if token_raw.len() != 32 {
-        return Err(ApiError::Unauthorized);
+    signed: Option<Extension<SignedOwner>>,
+) -> Result<Json<MailboxStatusResp>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Poll).await?;
+
+    let (tier_limits, limits) = mailbox_limits(&state, &mailbox_id).await?;
+    let (tier, created_at, queued_messages, queued_bytes): (Option<String>, i64, i64, i64) =
+        sqlx::query_as(
+            "SELECT tier, created_at, queued_count, queued_bytes FROM mailboxes WHERE mailbox_id = ?",
+        )
+        .bind(&mailbox_id)
+        .fetch_one(&state.shard(&mailbox_id).db)
+        .await
+        .map_err(|_| ApiError::ServerError)?;
+
+    Ok(Json(MailboxStatusResp {
+        tier,
+        limits,
+        tier_limits,
+        queued_messages,
+        queued_bytes,
+        created_at,
+    }))
+}
+
+#[derive(Deserialize)]
+struct SetLimitsReq {
+    max_msg_bytes: Option<i64>,
+    max_queue_bytes: Option<i64>,
+    max_queue_messages: Option<i64>,
+    ttl_secs: Option<i64>,
+    max_ttl_secs: Option<i64>,
+    ttl_days: Option<i64>,     // deprecated, used if ttl_secs is absent
+    max_ttl_days: Option<i64>, // deprecated, used if max_ttl_secs is absent
+}
+
+// Lets the owner lower limits within the mailbox's allowance. Absent fields
+// are left unchanged; setting a field to the allowance undoes a lowering.
+async fn set_limits(
+    State(state): State<Arc<AppState>>,
+    Path(mailbox_id): Path<String>,
+    headers: HeaderMap,
+    signed: Option<Extension<SignedOwner>>,
+    Payload(req): Payload<SetLimitsReq>,
+) -> Result<Json<Limits>, ApiError> {
+    auth_owner(&state, &mailbox_id, &headers, signed.as_deref(), Op::Manage).await?;
+
+    let (allowed, _) = mailbox_limits(&state, &mailbox_id).await?;
+    let ttl_secs = ttl_secs_or_days(req.ttl_secs, req.ttl_days);
+    let max_ttl_secs = ttl_secs_or_days(req.max_ttl_secs, req.max_ttl_days);
+    let within = |v: Option<i64>, max: i64| v.is_none_or(|v| (1..=max).contains(&v));
+    if !within(req.max_msg_bytes, allowed.max_msg_bytes as i64)
+        || !within(req.max_queue_bytes, allowed.max_queue_bytes)
+        || !within(req.max_queue_messages, allowed.max_queue_messages)
+        || !within(ttl_secs, allowed.ttl_secs)
+        || !within(max_ttl_secs, allowed.max_ttl_secs)
+    {
+        return Err(ApiError::InvalidInput);
     }
-    let poll_hash = hmac_hash(&state.server_secret, &token_raw);
 
-    let mb: Option<(Vec<u8>,)> =
-        sqlx::query_as("SELECT poll_hash FROM mailboxes WHERE mailbox_id = ?")
-            .bind(&mailbox_id)
-            .fetch_optional(&state.db)
-            .await
-            .map_err(|_| ApiError::ServerError)?;
-    let Some((stored_hash,)) = mb else {
-        return Err(ApiError::NotFound);
-    };
+    sqlx::query(
+        "UPDATE mailboxes SET owner_max_msg_bytes = COALESCE(?, owner_max_msg_bytes), owner_max_queue_bytes = COALESCE(?, owner_max_queue_bytes), owner_max_queue_messages = COALESCE(?, owner_max_queue_messages), owner_ttl_secs = COALESCE(?, owner_ttl_secs), owner_max_ttl_secs = COALESCE(?, owner_max_ttl_secs) WHERE mailbox_id = ?",
+    )
+    .bind(req.max_msg_bytes)
+    .bind(req.max_queue_bytes)
+    .bind(req.max_queue_messages)
+    .bind(ttl_secs)
+    .bind(max_ttl_secs)
+    .bind(&mailbox_id)
+    .execute(&state.shard(&mailbox_id).write_db)
+    .await
+    .map_err(|_| ApiError::ServerError)?;
+    state
+        .auth_cache
+        .invalidate(&AuthKey::Limits(mailbox_id.clone()));
 
-    if stored_hash != poll_hash {
-        return Err(ApiError::Forbidden);
+    let (_, limits) = mailbox_limits(&state, &mailbox_id).await?;
+    Ok(Json(limits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    // Scratch database file, removed with its WAL files when dropped
+    struct TestDb(std::path::PathBuf);
+
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{suffix}", self.0.display()));
+            }
+        }
     }
 
-    if req.msg_ids.is_empty() || req.msg_ids.len() > 2000 {
-        return Err(ApiError::InvalidInput);
+    async fn test_state() -> (Arc<AppState>, TestDb) {
+        let path = env::temp_dir().join(format!("whisper-mailbox-test-{}.db", random_b64url(12)));
+        let opts = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let shard = Shard::open(opts, 256).await.unwrap();
+        let state = AppState {
+            shards: vec![shard],
+            server_secret: b"test-secret".to_vec(),
+            default_ttl_secs: 7 * 86_400,
+            max_ttl_secs: 14 * 86_400,
+            max_msg_bytes: 16_384,
+            max_queue_bytes: 10_485_760,
+            max_queue_messages: 10_000,
+            poll_limit_default: 20,
+            poll_limit_max: 50,
+            prekey_low_watermark: 10,
+            max_one_time_prekeys: 500,
+            max_blob_bytes: 65_536,
+            max_blobs_per_mailbox: 16,
+            default_blob_ttl_days: 30,
+            max_blob_ttl_days: 365,
+            privacy_mode: false,
+            auth_failure_floor: Duration::ZERO,
+            owner_sig_max_skew_secs: 300,
+            owner_nonces: Arc::new(Mutex::new(HashMap::new())),
+            admin_token: None,
+            require_invite: false,
+            pow_difficulty_bits: 0,
+            pow_challenge_ttl_secs: 300,
+            pow_spent: Arc::new(Mutex::new(HashMap::new())),
+            tiers: HashMap::new(),
+            auth_cache: Arc::new(AuthCache::new(1000, 60)),
+        };
+        (Arc::new(state), TestDb(path))
     }
 
-    let mut deleted_total: u64 = 0;
-    for msg_id in req.msg_ids {
-        let raw = b64url_decode(&msg_id)?;
-        let res = sqlx::query("DELETE FROM messages WHERE mailbox_id = ? AND msg_id = ?")
-            .bind(&mailbox_id)
-            .bind(raw)
-            .execute(&state.db)
+    fn payload<T: DeserializeOwned>(v: serde_json::Value) -> Payload<T> {
+        Payload(serde_json::from_value(v).unwrap())
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        with_header("authorization", &format!("Bearer {token}"))
+    }
+
+    // Returns (mailbox_id, poll_token)
+    async fn new_mailbox(state: &Arc<AppState>) -> (String, String) {
+        let Json(resp) = create_mailbox(State(state.clone()), payload(serde_json::json!({})))
             .await
-            .map_err(|_| ApiError::ServerError)?;
-        deleted_total += res.rows_affected();
+            .unwrap();
+        (resp.mailbox_id, resp.poll_token.unwrap())
     }
 
-    Ok(Json(AckResp { deleted: deleted_total }))
-}
+    async fn register_token(
+        state: &Arc<AppState>,
+        mailbox_id: &str,
+        poll_token: &str,
+        entry: serde_json::Value,
+    ) {
+        let registered = register_deposit_tokens(
+            State(state.clone()),
+            Path(mailbox_id.to_string()),
+            bearer(poll_token),
+            None,
+            payload(serde_json::json!({ "deposit_tokens": [entry] })),
+        );
+        assert!(registered.await.is_ok());
+    }
 
-#[derive(Deserialize)]
-struct RevokeReq {
-    deposit_token_hashes: Vec<String>, // base64url(32 bytes)
-}
+    // Returns (queued_count, queued_bytes) after checking them against the messages
+    async fn queue_counters(state: &AppState, mailbox_id: &str) -> (i64, i64) {
+        let db = &state.shard(mailbox_id).db;
+        let counters: (i64, i64) =
+            sqlx::query_as("SELECT queued_count, queued_bytes FROM mailboxes WHERE mailbox_id = ?")
+                .bind(mailbox_id)
+                .fetch_one(db)
+                .await
+                .unwrap();
+        let actual: (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(SUM(LENGTH(blob)), 0) FROM messages WHERE mailbox_id = ?",
+        )
+        .bind(mailbox_id)
+        .fetch_one(db)
+        .await
+        .unwrap();
+        assert_eq!(counters, actual);
+        counters
+    }
 
-#[derive(Serialize)]
-struct RevokeResp {
-    revoked: u64,
-}
+    fn caveats(cs: &[&str]) -> Vec<String> {
+        cs.iter().map(|c| c.to_string()).collect()
+    }
 
-async fn revoke(
-    State(state): State<Arc<AppState>>,
-    Path(mailbox_id): Path<String>,
-    headers: HeaderMap,
-    Json(req): Json<RevokeReq>,
-) -> Result<Json<RevokeResp>, ApiError> {
-    // Auth poll token
-    let token = bearer_token(&headers)?;
-    let token_raw = b64url_decode(&token)?;
-    if token_raw.len() != 32 {
-        return Err(ApiError::Unauthorized);
+    #[test]
+    fn macaroon_attenuation_narrows_and_tampering_fails() {
+        let root_key = macaroon_root_key(b"secret", "mb", b"nonce");
+        let sig = macaroon_chain(&root_key, b"id", &[])
+            .finalize()
+            .into_bytes();
+
+        // A holder appends a caveat offline by chaining from the signature alone
+        let mut mac = HmacSha256::new_from_slice(&sig).unwrap();
+        mac.update(b"ops=poll");
+        let narrowed = mac.finalize().into_bytes();
+        let cs = caveats(&["ops=poll"]);
+        assert!(macaroon_chain(&root_key, b"id", &cs)
+            .verify_slice(&narrowed)
+            .is_ok());
+        assert!(macaroon_permits(&cs, Op::Poll, 0, 0));
+        assert!(!macaroon_permits(&cs, Op::Ack, 0, 0));
+        assert!(!macaroon_permits(&cs, Op::Deposit, 0, 0));
+
+        // Dropping the caveat or altering the signature breaks verification
+        assert!(macaroon_chain(&root_key, b"id", &[])
+            .verify_slice(&narrowed)
+            .is_err());
+        let mut tampered = narrowed.to_vec();
+        tampered[0] ^= 1;
+        assert!(macaroon_chain(&root_key, b"id", &cs)
+            .verify_slice(&tampered)
+            .is_err());
     }
-    let poll_hash = hmac_hash(&state.server_secret, &token_raw);
 
-    let mb: Option<(Vec<u8>,)> =
-        sqlx::query_as("SELECT poll_hash FROM mailboxes WHERE mailbox_id = ?")
-            .bind(&mailbox_id)
-            .fetch_optional(&state.db)
-            .await
-            .map_err(|_| ApiError::ServerError)?;
-    let Some((stored_hash,)) = mb else {
-        return Err(ApiError::NotFound);
-    };
+    #[test]
+    fn macaroon_caveats() {
+        let cs = caveats(&["expires=100", "max_msg_bytes=10", "deposit_until=50"]);
+        assert!(macaroon_permits(&cs, Op::Deposit, 49, 10));
+        assert!(!macaroon_permits(&cs, Op::Deposit, 49, 11));
+        assert!(!macaroon_permits(&cs, Op::Deposit, 50, 10));
+        assert!(macaroon_permits(&cs, Op::Poll, 99, 0));
+        assert!(!macaroon_permits(&cs, Op::Poll, 100, 0));
+        assert!(!macaroon_permits(&caveats(&["bogus=1"]), Op::Poll, 0, 0));
+        assert!(!macaroon_permits(
+            &caveats(&["ops=poll,admin"]),
+            Op::Poll,
+            0,
+            0
+        ));
+    }
 
-    if stored_hash != poll_hash {
-        return Err(ApiError::Forbidden);
+    #[test]
+    fn macaroon_never_grants_admin() {
+        assert!(macaroon_permits(&[], Op::Manage, 0, 0));
+        assert!(!macaroon_permits(&[], Op::Admin, 0, 0));
+        assert!(!macaroon_permits(
+            &caveats(&["expires=100"]),
+            Op::Admin,
+            0,
+            0
+        ));
     }
 
-    if req.deposit_token_hashes.is_empty() || req.deposit_token_hashes.len() > 1000 {
-        return Err(ApiError::InvalidInput);
+    fn with_header(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
     }
 
-    let mut revoked_total: u64 = 0;
-    for h in req.deposit_token_hashes {
-        let raw = b64url_decode(&h)?;
-        if raw.len() != 32 {
-            return Err(ApiError::InvalidInput);
+    #[test]
+    fn byte_ranges() {
+        let range = |spec: &str, size| byte_range(&with_header("range", spec), size);
+        assert_eq!(byte_range(&HeaderMap::new(), 10), Ok(None));
+        assert_eq!(range("bytes=2-5", 10), Ok(Some((2, 4))));
+        assert_eq!(range("bytes=0-0", 10), Ok(Some((0, 1))));
+        assert_eq!(range("bytes=4-", 10), Ok(Some((4, 6))));
+        assert_eq!(range("bytes=5-100", 10), Ok(Some((5, 5))));
+
+        // Suffix ranges, longer than the blob clamp to all of it
+        assert_eq!(range("bytes=-3", 10), Ok(Some((7, 3))));
+        assert_eq!(range("bytes=-30", 10), Ok(Some((0, 10))));
+        assert_eq!(range("bytes=-0", 10), Ok(None));
+
+        // Starting at or past the end is unsatisfiable, as is any range of an empty blob
+        assert_eq!(range("bytes=10-", 10), Err(()));
+        assert_eq!(range("bytes=10-20", 10), Err(()));
+        assert_eq!(range("bytes=0-", 0), Err(()));
+        assert_eq!(range("bytes=-5", 0), Err(()));
+
+        // Unsupported or malformed: serve the whole blob
+        assert_eq!(range("bytes=0-1,4-5", 10), Ok(None));
+        assert_eq!(range("bytes=5-2", 10), Ok(None));
+        assert_eq!(range("bytes=x-y", 10), Ok(None));
+        assert_eq!(range("items=0-1", 10), Ok(None));
+    }
+
+    #[test]
+    fn poll_format_follows_accept_q_values() {
+        let format = |accept: &str| poll_format(&with_header("accept", accept));
+        assert!(poll_format(&HeaderMap::new()) == PollFormat::Json);
+        assert!(format("*/*") == PollFormat::Json);
+        assert!(format("application/cbor") == PollFormat::Cbor);
+        assert!(format("Application/Vnd.Whisper.Frames") == PollFormat::Frames);
+
+        // Highest q wins, ties go to the first listed
+        assert!(format("application/json;q=0.5, application/cbor") == PollFormat::Cbor);
+        assert!(format("application/cbor;q=0.2, application/json;q=0.9") == PollFormat::Json);
+        assert!(format("application/cbor, application/vnd.whisper.frames") == PollFormat::Cbor);
+        assert!(format("text/html, application/vnd.whisper.frames; q=0.8") == PollFormat::Frames);
+
+        // q=0 means not acceptable
+        assert!(format("application/cbor;q=0") == PollFormat::Json);
+    }
+
+    #[test]
+    fn expiry_headers() {
+        let expiry = |name, value: &str| header_expiry(&with_header(name, value));
+        assert!(matches!(header_expiry(&HeaderMap::new()), Ok(None)));
+        assert!(matches!(
+            expiry("x-whisper-expiresat", "1700000000"),
+            Ok(Some(Expiry::At(1_700_000_000)))
+        ));
+        assert!(matches!(
+            expiry("x-whisper-expires-at", " 1700000000 "),
+            Ok(Some(Expiry::At(1_700_000_000)))
+        ));
+        assert!(matches!(
+            expiry("x-whisper-expiresat", "2023-11-14T22:13:20Z"),
+            Ok(Some(Expiry::At(1_700_000_000)))
+        ));
+        assert!(matches!(
+            expiry("x-whisper-expiresat", "2023-11-15T00:13:20+02:00"),
+            Ok(Some(Expiry::At(1_700_000_000)))
+        ));
+        assert!(matches!(
+            expiry("x-whisper-ttl", "3600"),
+            Ok(Some(Expiry::Ttl(3600)))
+        ));
+        assert_eq!(Expiry::Ttl(3600).resolve(1000), 4600);
+
+        // Garbage, non-positive TTLs and both headers at once are rejected
+        assert!(expiry("x-whisper-expiresat", "tomorrow").is_err());
+        assert!(expiry("x-whisper-expiresat", "2023-11-14").is_err());
+        assert!(expiry("x-whisper-ttl", "0").is_err());
+        assert!(expiry("x-whisper-ttl", "-5").is_err());
+        assert!(expiry("x-whisper-ttl", "1h").is_err());
+        let mut both = with_header("x-whisper-expiresat", "1700000000");
+        both.insert("x-whisper-ttl", "60".parse().unwrap());
+        assert!(header_expiry(&both).is_err());
+    }
+
+    #[test]
+    fn owner_nonces_are_single_use_up_to_the_window_edge() {
+        let nonces = Mutex::new(HashMap::new());
+        let spend = |nonce: &str, now| spend_nonce(&nonces, format!("mb:{nonce}"), 1000, 300, now);
+
+        // Stale or future timestamps are rejected outright
+        assert!(matches!(spend("a", 1301), Err(ApiError::Unauthorized)));
+        assert!(matches!(spend("a", 699), Err(ApiError::Unauthorized)));
+
+        assert!(spend("a", 1000).is_ok());
+        assert!(matches!(spend("a", 1000), Err(ApiError::Unauthorized)));
+        // The last second the timestamp is accepted, the nonce is still remembered
+        assert!(matches!(spend("a", 1300), Err(ApiError::Unauthorized)));
+        assert!(spend("b", 1300).is_ok());
+        assert!(matches!(spend("b", 1300), Err(ApiError::Unauthorized)));
+
+        // Pruning a full cache keeps nonces whose window hasn't closed
+        let mut full: HashMap<String, i64> = (0..MAX_OWNER_NONCES - 1)
+            .map(|i| (format!("old:{i}"), 1299))
+            .collect();
+        full.insert("mb:c".into(), 1300);
+        let nonces = Mutex::new(full);
+        assert!(matches!(
+            spend_nonce(&nonces, "mb:c".into(), 1000, 300, 1300),
+            Err(ApiError::Unauthorized)
+        ));
+        assert_eq!(nonces.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sender_bound_tokens_must_sign_prekey_claims() {
+        let (state, _db) = test_state().await;
+        let (mailbox_id, poll_token) = new_mailbox(&state).await;
+        let sender = SigningKey::from_bytes(&[7; 32]);
+        let token = b64url_encode(&[1; 32]);
+        let entry = serde_json::json!({
+            "token": token,
+            "sender_pubkey": b64url_encode(sender.verifying_key().as_bytes()),
+        });
+        register_token(&state, &mailbox_id, &poll_token, entry).await;
+        let prekeys = serde_json::json!({
+            "signed_prekey": { "key_id": 1, "public_key": b64url_encode(&[2; 32]), "signature": b64url_encode(&[3; 64]) },
+            "one_time_prekeys": [{ "key_id": 1, "public_key": b64url_encode(&[4; 32]) }],
+        });
+        let uploaded = upload_prekeys(
+            State(state.clone()),
+            Path(mailbox_id.clone()),
+            bearer(&poll_token),
+            None,
+            payload(prekeys),
+        );
+        assert!(uploaded.await.is_ok());
+
+        let signed = |key: &SigningKey, nonce: &[u8]| {
+            let (timestamp, nonce) = (unix_ts(), b64url_encode(nonce));
+            let sig = key
+                .sign(format!("whisper-prekey-v1\n{mailbox_id}\n{timestamp}\n{nonce}").as_bytes());
+            let mut headers = bearer(&token);
+            headers.insert("x-whisper-sender-timestamp", timestamp.into());
+            headers.insert("x-whisper-sender-nonce", nonce.parse().unwrap());
+            headers.insert(
+                "x-whisper-sender-signature",
+                b64url_encode(&sig.to_bytes()).parse().unwrap(),
+            );
+            headers
+        };
+        let claim =
+            |headers| claim_prekey_bundle(State(state.clone()), Path(mailbox_id.clone()), headers);
+
+        // The token alone, or signed by another key, gets nothing
+        assert!(matches!(
+            claim(bearer(&token)).await,
+            Err(ApiError::Unauthorized)
+        ));
+        let stranger = SigningKey::from_bytes(&[8; 32]);
+        assert!(matches!(
+            claim(signed(&stranger, &[5; 16])).await,
+            Err(ApiError::Forbidden)
+        ));
+
+        // Signed by the sender key: the one-time prekey is still there, once
+        let headers = signed(&sender, &[6; 16]);
+        let Json(bundle) = claim(headers.clone()).await.unwrap();
+        assert!(bundle.one_time_prekey.is_some());
+        assert!(matches!(claim(headers).await, Err(ApiError::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn queue_quota_holds_under_concurrent_deposits() {
+        let (state, _db) = test_state().await;
+        let (mailbox_id, poll_token) = new_mailbox(&state).await;
+        let token = b64url_encode(&[1; 32]);
+        register_token(&state, &mailbox_id, &poll_token, serde_json::json!(token)).await;
+        let limits = serde_json::json!({ "max_queue_messages": 3, "max_queue_bytes": 300 });
+        let lowered = set_limits(
+            State(state.clone()),
+            Path(mailbox_id.clone()),
+            bearer(&poll_token),
+            None,
+            payload(limits),
+        );
+        assert!(lowered.await.is_ok());
+
+        let send = |n: u8, size: usize| {
+            let mut headers = bearer(&token);
+            headers.insert("x-whisper-msgid", b64url_encode(&[n; 16]).parse().unwrap());
+            deposit(
+                State(state.clone()),
+                Path(mailbox_id.clone()),
+                headers,
+                Bytes::from(vec![n; size]),
+            )
+        };
+
+        // Ten racing deposits for three slots
+        let racing: Vec<_> = (0..10).map(|n| tokio::spawn(send(n, 60))).collect();
+        let mut stored = 0;
+        for task in racing {
+            match task.await.unwrap() {
+                Ok(_) => stored += 1,
+                Err(e) => assert!(matches!(e, ApiError::QuotaExceeded("queue_messages"))),
+            }
         }
-        let res = sqlx::query(
-            "UPDATE deposit_tokens SET revoked = 1 WHERE mailbox_id = ? AND dep_hash = ?",
+        assert_eq!(stored, 3);
+        assert_eq!(queue_counters(&state, &mailbox_id).await, (3, 180));
+
+        // Acking releases the queue; the byte quota is exact at its edge too
+        let acked = ack(
+            State(state.clone()),
+            Path(mailbox_id.clone()),
+            bearer(&poll_token),
+            None,
+            payload(
+                serde_json::json!({ "msg_ids": (0..10).map(|n| b64url_encode(&[n; 16])).collect::<Vec<_>>() }),
+            ),
+        );
+        assert!(acked.await.is_ok());
+        assert_eq!(queue_counters(&state, &mailbox_id).await, (0, 0));
+        assert!(send(20, 100).await.is_ok());
+        assert!(send(21, 100).await.is_ok());
+        assert!(matches!(
+            send(22, 101).await,
+            Err(ApiError::QuotaExceeded("queue_bytes"))
+        ));
+        assert!(send(23, 100).await.is_ok());
+        assert!(matches!(
+            send(24, 1).await,
+            Err(ApiError::QuotaExceeded("queue_messages"))
+        ));
+        assert_eq!(queue_counters(&state, &mailbox_id).await, (3, 300));
+
+        // Expiry releases the rest
+        purge_expired_messages(&state.shard(&mailbox_id).write_db, i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(queue_counters(&state, &mailbox_id).await, (0, 0));
+    }
+
+    #[tokio::test]
+    async fn failed_write_job_rolls_back_only_its_savepoint() {
+        let (state, _db) = test_state().await;
+        let (mailbox_id, _) = new_mailbox(&state).await;
+        let message = |n: u8, body: &[u8]| NewMessage {
+            mailbox_id: mailbox_id.clone(),
+            msg_id: vec![n; 16],
+            blob: Bytes::copy_from_slice(body),
+            body_hash: Sha256::digest(body).to_vec(),
+            dep_hash: None,
+            sender_sig: None,
+            alias_id: None,
+            address: None,
+            hint: Some(body.to_vec()),
+            received_at: 0,
+            expires_at: i64::MAX,
+            max_queue_messages: 10,
+            max_queue_bytes: 1000,
+        };
+
+        // Queued before the writer starts, so they make up one batch. The
+        // second deposit reuses the first's msg_id: it reserves queue space
+        // and then fails on the insert.
+        let (jobs, rx) = mpsc::channel(8);
+        let (first, first_rx) = oneshot::channel();
+        let (dup, dup_rx) = oneshot::channel();
+        let (third, third_rx) = oneshot::channel();
+        let (acked, ack_rx) = oneshot::channel();
+        jobs.send(WriteJob::Deposit(message(1, b"first"), first))
+            .await
+            .unwrap();
+        jobs.send(WriteJob::Deposit(message(1, b"other"), dup))
+            .await
+            .unwrap();
+        jobs.send(WriteJob::Deposit(message(3, b"third"), third))
+            .await
+            .unwrap();
+        let ack = WriteJob::Ack {
+            mailbox_id: mailbox_id.clone(),
+            msg_ids: vec![vec![3; 16]],
+            reply: acked,
+        };
+        jobs.send(ack).await.unwrap();
+        drop(jobs);
+        run_writer(state.shard(&mailbox_id).write_db.clone(), rx, 16).await;
+
+        assert!(matches!(first_rx.await.unwrap(), Ok(())));
+        assert!(matches!(dup_rx.await.unwrap(), Err(ApiError::Conflict)));
+        assert!(matches!(third_rx.await.unwrap(), Ok(())));
+        assert!(matches!(ack_rx.await.unwrap(), Ok(1)));
+
+        // Only the first deposit remains, with its own blob and hint, and the
+        // conflicting job's reservation was undone
+        let db = &state.shard(&mailbox_id).db;
+        let rows: Vec<(Vec<u8>, Vec<u8>)> = sqlx::query_as(
+            "SELECT m.blob, h.hint FROM messages m JOIN message_hints h USING (mailbox_id, msg_id)",
         )
-        .bind(&mailbox_id)
-        .bind(raw)
-        .execute(&state.db)
+        .fetch_all(db)
         .await
-        .map_err(|_| ApiError::ServerError)?;
-        revoked_total += res.rows_affected();
+        .unwrap();
+        assert_eq!(rows, vec![(b"first".to_vec(), b"first".to_vec())]);
+        assert_eq!(queue_counters(&state, &mailbox_id).await, (1, 5));
     }
 
-    Ok(Json(RevokeResp {
-        revoked: revoked_total,
-    }))
-}
+    #[test]
+    fn auth_cache_drops_lookups_that_raced_an_invalidation() {
+        let cache = AuthCache::new(10, 60);
+        let key = AuthKey::Deposit("mb".into(), vec![1; 32]);
 
+        let generation = cache.generation();
+        cache.put(key.clone(), AuthEntry::Deposit(None), generation);
+        assert!(cache.get(&key).is_some());
+
+        // A check read the token as live, then the revocation committed and
+        // invalidated before the check got to cache its result
+        let generation = cache.generation();
+        cache.invalidate(&key);
+        cache.put(key.clone(), AuthEntry::Deposit(None), generation);
+        assert!(cache.get(&key).is_none());
+
+        // Whole-mailbox invalidation counts too
+        let generation = cache.generation();
+        cache.invalidate_mailbox("other");
+        cache.put(key.clone(), AuthEntry::Deposit(None), generation);
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn limits_keep_the_whole_day_ttl_fields() {
+        let tier: TierLimits =
+            serde_json::from_str(r#"{"ttl_days":2,"max_ttl_secs":3600}"#).unwrap();
+        assert_eq!(
+            ttl_secs_or_days(tier.ttl_secs, tier.ttl_days),
+            Some(172_800)
+        );
+        assert_eq!(
+            ttl_secs_or_days(tier.max_ttl_secs, tier.max_ttl_days),
+            Some(3600)
+        );
+        assert_eq!(ttl_secs_or_days(Some(60), Some(1)), Some(60));
+        assert!(serde_json::from_str::<TierLimits>(r#"{"ttl_hours":1}"#).is_err());
+
+        let limits = Limits {
+            max_msg_bytes: 1,
+            max_queue_bytes: 1,
+            max_queue_messages: 1,
+            ttl_secs: 129_600,
+            max_ttl_secs: 3600,
+        };
+        let json = serde_json::to_value(limits).unwrap();
+        assert_eq!(json["ttl_secs"], 129_600);
+        assert_eq!(json["ttl_days"], 1);
+        assert_eq!(json["max_ttl_secs"], 3600);
+        assert_eq!(json["max_ttl_days"], 0);
+    }
+}